@@ -0,0 +1,616 @@
+use crate::errors::{INTERRUPT, INTERRUPT_GRACE, interrupted};
+use crate::output::{self, status, verbose, warning};
+use crate::paths::{is_stdout, output_dir_prefix, prepare_savepath, resolve_savepath};
+use crate::probe::probe_expected;
+use crate::strategy::{MULTI_THREAD_MIN_SIZE, Strategy, decide_strategy};
+use crate::throttle::Quota;
+use crate::options::Options;
+use crate::{
+    Backend, DownloadOutcome, HashStatus, auto_threads, client, config, display_url, download_threads, hash, metrics,
+    normalize_url, run_download, units,
+};
+use anyhow::anyhow;
+use clap::{CommandFactory, FromArgMatches};
+use std::sync::Arc;
+use tokio::time::Duration;
+use url::Url;
+
+#[derive(Clone, clap::Parser)]
+#[command(name = "rget")]
+#[command(author = "DCRlike")]
+#[command(version = "0.1")]
+#[command(about = "A simple Rust-based wget alternative", long_about = None)]
+pub struct Cli {
+    /// The URL to download. Read from stdin when neither this nor
+    /// `--input-file` is given and stdin isn't a terminal
+    pub url: Option<String>,
+
+    #[command(flatten)]
+    pub options: Options,
+
+    /// Only check that the URL is reachable and describe it, without downloading
+    #[arg(long, conflicts_with_all = ["savepath", "resume"])]
+    pub spider: bool,
+
+    /// Probe the URL and print the download plan (mode, chunks, output path)
+    /// without writing anything
+    #[arg(long, conflicts_with_all = ["spider", "input_file"])]
+    pub dry_run: bool,
+
+    /// Read URLs to download from a file, one per line, or from stdin with `-`
+    #[arg(short, long)]
+    pub input_file: Option<String>,
+
+    /// Stop once this much has been downloaded in total, e.g. `500M`. The
+    /// download in progress is stopped too and can be resumed with --continue
+    #[arg(short = 'Q', long, value_name = "SIZE", value_parser = units::parse_size)]
+    pub quota: Option<u64>,
+
+    /// Seconds to wait between downloads when using --input-file
+    #[arg(short = 'w', long, value_name = "SECS")]
+    pub wait: Option<u64>,
+
+    /// Vary --wait by up to 50% either way
+    #[arg(long, requires = "wait")]
+    pub random_wait: bool,
+
+    /// Stop at the first failed download when using --input-file
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Prompt for the --user password without echoing it, instead of taking
+    /// it from --password where it shows up in shell history and `ps`
+    #[arg(long, conflicts_with = "password")]
+    pub ask_password: bool,
+
+    /// How to show download progress
+    #[arg(long, value_enum, default_value_t = output::ProgressMode::Aggregate)]
+    pub progress: output::ProgressMode,
+
+    /// Format of the progress bar, as an indicatif template, e.g.
+    /// `{bar:40} {percent}% {binary_bytes_per_sec}`. `{smooth_speed}` and
+    /// `{smooth_eta}` average over --speed-window
+    #[arg(long, value_name = "TEMPLATE")]
+    pub progress_template: Option<String>,
+
+    /// Rewrite FILE every second with a JSON snapshot of the progress
+    /// (bytes downloaded, total, speed and each thread's share), for dashboards.
+    /// A named pipe gets one line per snapshot
+    #[arg(long, value_name = "FILE")]
+    pub progress_file: Option<String>,
+
+    /// Append a timestamped record of each step (probe results, strategy,
+    /// threads, retries, hash checks, errors) to FILE. --quiet doesn't shorten
+    /// it; --verbose adds the detail it prints
+    #[arg(long, value_name = "FILE")]
+    pub log_file: Option<String>,
+
+    /// Average the speed and ETA on progress bars over this many seconds
+    #[arg(long, value_name = "SECS", default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub speed_window: u64,
+
+    /// Don't show any progress, same as `--progress=none`
+    #[arg(long, conflicts_with = "progress")]
+    pub no_progress: bool,
+
+    /// Suppress all output except errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Log request and response headers to stderr (repeat for more detail)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Print a JSON summary of each download to stdout instead of progress output
+    #[arg(long)]
+    pub json: bool,
+
+    /// After the downloads finish, write Prometheus metrics (bytes, duration,
+    /// retries, threads) for each successful one to FILE, labeled by host,
+    /// filename and savepath, for the node_exporter textfile collector
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["spider", "dry_run"])]
+    pub metrics_file: Option<String>,
+
+    /// Read default options from this TOML file instead of `~/.rgetrc`
+    #[arg(long, value_name = "FILE", conflicts_with = "no_config")]
+    pub config: Option<String>,
+
+    /// Don't read `~/.rgetrc`
+    #[arg(long)]
+    pub no_config: bool,
+}
+
+/// The `--json` object for one URL, with an `error` field if it failed
+fn json_summary(url: &str, result: &anyhow::Result<DownloadOutcome>) -> serde_json::Value {
+    match result {
+        Ok(outcome) => {
+            let mut summary = serde_json::json!({
+                "url": display_url(url),
+                "final_url": display_url(outcome.final_url()),
+                "savepath": outcome.savepath(),
+                "total_bytes": outcome.total_bytes(),
+                "elapsed_ms": outcome.elapsed().as_millis() as u64,
+                "threads_used": outcome.mode().threads(),
+                "mode": outcome.mode().name(),
+                "hash_verified": outcome.hash_status() == HashStatus::Verified,
+            });
+            if let Some((algorithm, hex)) = outcome.digest() {
+                summary["hash"] = format!("{}:{}", algorithm, hex).into();
+            }
+            summary
+        }
+        Err(e) => serde_json::json!({
+            "url": display_url(url),
+            "error": e.to_string(),
+        }),
+    }
+}
+
+/// `--print-hash`: the digest and path in the format `sha256sum` prints
+fn print_digest(outcome: &DownloadOutcome) {
+    if let Some((_, hex)) = outcome.digest() {
+        println!("{}  {}", hex, outcome.savepath());
+    }
+}
+
+/// The `--metrics-file` series for one completed download
+fn metrics_sample(outcome: &DownloadOutcome) -> metrics::Sample {
+    let host = Url::parse(outcome.final_url())
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let filename = std::path::Path::new(outcome.savepath())
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| outcome.savepath().to_string());
+    metrics::Sample {
+        host,
+        filename,
+        savepath: outcome.savepath().to_string(),
+        bytes: outcome.total_bytes(),
+        duration: outcome.elapsed(),
+        retries: outcome.retries(),
+        threads: outcome.mode().threads(),
+    }
+}
+
+/// Write `--metrics-file`; failing to is only a warning, the downloads are done
+async fn write_metrics(args: &Cli, samples: &[metrics::Sample]) {
+    if let Some(ref path) = args.metrics_file
+        && let Err(e) = metrics::write(path, samples).await
+    {
+        warning!("Warning: {}", e);
+    }
+}
+
+/// Run rget as the command line tool: parse the arguments, then download,
+/// probe or check every URL they name, exiting with 1 if any failed
+pub async fn run() -> anyhow::Result<()> {
+    // Parse command line arguments, then fill in the rest from the config file
+    let matches = Cli::command().get_matches();
+    let mut args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if !args.no_config
+        && let Some(config) = config::Config::load(args.config.as_deref())?
+    {
+        config.apply(&mut args, &matches)?;
+    }
+    if args.url.is_none() && args.input_file.is_none() {
+        args.url = Some(url_from_stdin()?);
+    }
+    // 之后所有地方都只用规范化后的URL
+    if let Some(ref url) = args.url {
+        args.url = Some(normalize_url(url)?);
+    }
+    args.options.mirrors = args.options.mirrors.iter().map(|m| normalize_url(m)).collect::<anyhow::Result<_>>()?;
+    // --verbose也打印每个线程的统计
+    args.options.stats |= args.verbose > 0;
+    if let Some(ref path) = args.log_file {
+        output::set_log_file(path)?;
+    }
+    // --json时stdout只留给JSON，其余输出都关掉
+    if args.quiet || (args.json && args.verbose == 0) {
+        output::set_verbosity(output::Verbosity::Quiet);
+    } else if args.verbose > 1 {
+        output::set_verbosity(output::Verbosity::Trace);
+    } else if args.verbose == 1 {
+        output::set_verbosity(output::Verbosity::Verbose);
+    } else {
+        output::set_verbosity(output::Verbosity::Normal);
+    }
+    output::set_progress_mode(if args.no_progress { output::ProgressMode::None } else { args.progress });
+    output::set_speed_window(args.speed_window);
+    if let Some(ref template) = args.progress_template {
+        output::set_progress_template(template)?;
+    }
+    if let Some(ref path) = args.progress_file {
+        output::set_progress_file(path);
+    }
+
+    if args.options.threads == 0 {
+        args.options.threads = auto_threads();
+        status!("Auto-detected thread count: {}", args.options.threads);
+    }
+
+    if args.ask_password {
+        args.options.password = Some(ask_password(&args)?);
+    }
+
+    // 在发起请求之前先校验hash参数
+    let expected_hash = match args.options.hash {
+        Some(ref h) => Some(hash::ExpectedHash::parse(h)?),
+        None => None,
+    };
+
+    let mut urls: Vec<String> = args.url.iter().cloned().collect();
+    if let Some(ref input_file) = args.input_file {
+        urls.extend(read_url_list(input_file).await?);
+    }
+
+    // 所有请求共用一个client，复用连接池和配置
+    let client = client::build_client(&args.options, &urls)?;
+
+    // 第一次Ctrl-C让下载保存进度后退出；再按一次，或者收尾太久，就立即退出
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            INTERRUPT.cancel();
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = tokio::time::sleep(INTERRUPT_GRACE * 2) => {}
+            }
+            std::process::exit(130);
+        }
+    });
+
+    if args.spider {
+        let mut failed = 0;
+        for (i, url) in urls.iter().enumerate() {
+            if i > 0 && !batch_wait(&args).await {
+                break;
+            }
+            if let Err(e) = run_spider(&args, &client, url).await {
+                output::log_line(output::LogLevel::Error, format_args!("{}: {}", display_url(url), e));
+                if !args.json {
+                    eprintln!("{}: {}", display_url(url), e);
+                }
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // 整批下载共用一个配额
+    let quota = args.quota.map(|limit| Arc::new(Quota::new(limit)));
+
+    let output_dir = output_dir_prefix(&args.options);
+
+    if args.input_file.is_some() {
+        if args.options.savepath.is_some() {
+            return Err(anyhow!("--savepath can't be used with --input-file"));
+        }
+        if expected_hash.is_some() {
+            return Err(anyhow!("--hash can't be used with --input-file"));
+        }
+
+        let mut succeeded = 0;
+        let mut failed = 0;
+        let mut samples = Vec::new();
+        for (i, url) in urls.iter().enumerate() {
+            if let Some(ref quota) = quota
+                && quota.is_exhausted()
+            {
+                warning!(
+                    "Download quota of {} bytes reached, skipping the remaining {} URLs",
+                    args.quota.unwrap_or(0),
+                    urls.len() - i
+                );
+                break;
+            }
+            if i > 0 && !batch_wait(&args).await {
+                break;
+            }
+            let result = run_download(&args.options, &client, url, output_dir.as_deref(), None, quota.as_ref()).await;
+            if args.json {
+                println!("{}", json_summary(url, &result));
+            } else if let Ok(ref outcome) = result {
+                print_digest(outcome);
+            }
+            match result {
+                Ok(outcome) => {
+                    samples.push(metrics_sample(&outcome));
+                    succeeded += 1;
+                }
+                Err(e) => {
+                    output::log_line(output::LogLevel::Error, format_args!("{}: {}", display_url(url), e));
+                    if !args.json {
+                        eprintln!("{}: {}", display_url(url), e);
+                    }
+                    failed += 1;
+                    if args.strict || INTERRUPT.is_cancelled() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        write_metrics(&args, &samples).await;
+        status!("{} of {} downloads succeeded", succeeded, urls.len());
+        if let Some(ref quota) = quota {
+            status!("Downloaded {} bytes of the {} byte quota", quota.used(), args.quota.unwrap_or(0));
+        }
+        if INTERRUPT.is_cancelled() {
+            std::process::exit(130);
+        }
+        if failed > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let url = args
+        .url
+        .clone()
+        .ok_or_else(|| anyhow!("No URL given, pass a URL or --input-file"))?;
+    let savepath = args.options.savepath.as_deref().or(output_dir.as_deref());
+
+    if args.dry_run {
+        if let Err(e) = run_dry_run(&args, &client, &url, savepath).await {
+            output::log_line(output::LogLevel::Error, &e);
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if savepath.is_some_and(is_stdout) {
+        if args.json {
+            return Err(anyhow!("--json can't be used when writing to stdout"));
+        }
+        if expected_hash.is_some() {
+            return Err(anyhow!("--hash can't be used when writing to stdout"));
+        }
+        if args.options.checksum_file.is_some() {
+            return Err(anyhow!("--checksum-file can't be used when writing to stdout"));
+        }
+        if args.options.print_hash.is_some() {
+            return Err(anyhow!("--print-hash can't be used when writing to stdout"));
+        }
+        if args.options.manifest.is_some() {
+            return Err(anyhow!("--manifest can't be used when writing to stdout"));
+        }
+        if !args.options.mirrors.is_empty() {
+            return Err(anyhow!("--mirror can't be used when writing to stdout"));
+        }
+        if args.options.resume {
+            return Err(anyhow!("--continue can't be used when writing to stdout"));
+        }
+        if args.options.timestamping {
+            return Err(anyhow!("--timestamping can't be used when writing to stdout"));
+        }
+        if args.options.if_modified_since.is_some() {
+            return Err(anyhow!("--if-modified-since can't be used when writing to stdout"));
+        }
+        output::set_status_to_stderr(true);
+    }
+
+    let result = run_download(&args.options, &client, &url, savepath, expected_hash.as_ref(), quota.as_ref()).await;
+    if args.json {
+        println!("{}", json_summary(&url, &result));
+    } else if let Ok(ref outcome) = result {
+        print_digest(outcome);
+    }
+    let samples: Vec<_> = result.iter().map(metrics_sample).collect();
+    write_metrics(&args, &samples).await;
+    if let Err(e) = result {
+        output::log_line(output::LogLevel::Error, &e);
+        if !args.json {
+            eprintln!("{}", e);
+        }
+        std::process::exit(if INTERRUPT.is_cancelled() { 130 } else { 1 });
+    }
+    
+    Ok(())
+}
+
+/// Read the password for `--ask-password` from the terminal
+fn ask_password(args: &Cli) -> anyhow::Result<String> {
+    use std::io::IsTerminal;
+
+    // 用户名也可能来自配置文件，所以在这里而不是在clap里检查
+    let user = args.options.user.as_deref().ok_or_else(|| anyhow!("--ask-password needs --user"))?;
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow!("--ask-password needs a terminal to read the password from"));
+    }
+    rpassword::prompt_password(format!("Password for {}: ", user))
+        .map_err(|e| anyhow!("Failed to read the password: {}", e))
+}
+
+/// `--spider`: probe `url` and describe it without downloading anything.
+/// `--expect-type` and `--max-filesize` make the check fail like a download would
+async fn run_spider(args: &Cli, client: &reqwest::Client, url: &str) -> anyhow::Result<()> {
+    if Backend::for_url(url) != Backend::Http {
+        return Err(anyhow!("--spider only supports HTTP(S) URLs"));
+    }
+
+    let info = probe_expected(&args.options, client, url, Default::default()).await;
+    if args.json {
+        let summary = match info {
+            Ok(ref info) => serde_json::json!({
+                "url": display_url(url),
+                "final_url": display_url(&info.final_url),
+                "reachable": true,
+                "total_bytes": info.total_size,
+                "content_type": info.content_type,
+                "supports_ranges": info.supports_ranges,
+            }),
+            Err(ref e) => serde_json::json!({
+                "url": display_url(url),
+                "reachable": false,
+                "error": e.to_string(),
+            }),
+        };
+        println!("{}", summary);
+    }
+    let info = info?;
+
+    status!("Spider mode: {}", display_url(url));
+    if info.final_url != url {
+        status!("  Final URL: {}", display_url(&info.final_url));
+    }
+    match info.total_size {
+        Some(size) => status!("  Size: {} bytes", size),
+        None => status!("  Size: unknown"),
+    }
+    status!("  Content-Type: {}", info.content_type.as_deref().unwrap_or("unknown"));
+    status!("  Range requests: {}", if info.supports_ranges { "supported" } else { "not supported" });
+    status!("Remote file exists.");
+    Ok(())
+}
+
+/// Sleep for `--wait` between two downloads of a batch. Returns `false` when
+/// Ctrl-C cut the wait short
+async fn batch_wait(args: &Cli) -> bool {
+    let Some(secs) = args.wait else {
+        return true;
+    };
+    let mut wait = Duration::from_secs(secs);
+    if args.random_wait {
+        // 不需要很好的随机数，只要每次等待的时间不一样
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        wait = wait.mul_f64(0.5 + (nanos % 1000) as f64 / 1000.0);
+    }
+    verbose!("Waiting {:.1}s before the next download", wait.as_secs_f64());
+    tokio::select! {
+        _ = tokio::time::sleep(wait) => true,
+        _ = interrupted() => false,
+    }
+}
+
+/// `--dry-run`: probe `url` and print how it would be downloaded, without
+/// writing anything
+async fn run_dry_run(args: &Cli, client: &reqwest::Client, url: &str, savepath: Option<&str>) -> anyhow::Result<()> {
+    if Backend::for_url(url) != Backend::Http {
+        return Err(anyhow!("--dry-run only supports HTTP(S) URLs"));
+    }
+
+    let info = probe_expected(&args.options, client, url, Default::default()).await?;
+    let path = resolve_savepath(savepath, &info.final_url, info.filename.as_deref()).await?;
+    let path = if is_stdout(&path) { path } else { prepare_savepath(&args.options, &path, false).await? };
+
+    // 和download_file的判断保持一致，另外stdout只能单线程
+    let strategy = decide_strategy(
+        info.total_size,
+        info.supports_ranges,
+        download_threads(&args.options),
+        args.options.adaptive_threads,
+        args.options.segment_size,
+        args.options.prefer_single_thread_below.unwrap_or(MULTI_THREAD_MIN_SIZE),
+    );
+    let ranges = match strategy {
+        Strategy::Multi { ranges, .. } if !is_stdout(&path) => Some(ranges),
+        _ => None,
+    };
+    // 分段比线程多时，同时下载的只有线程数那么多
+    let threads_used = ranges.as_ref().map_or(1, |ranges| {
+        args.options.max_concurrent
+            .unwrap_or(download_threads(&args.options) as usize)
+            .clamp(1, ranges.len())
+    });
+
+    if args.json {
+        let chunks: Vec<_> = ranges
+            .iter()
+            .flatten()
+            .map(|&(start, end)| serde_json::json!({ "start": start, "end": end }))
+            .collect();
+        let summary = serde_json::json!({
+            "url": display_url(url),
+            "final_url": display_url(&info.final_url),
+            "savepath": path,
+            "total_bytes": info.total_size,
+            "supports_ranges": info.supports_ranges,
+            "mode": if ranges.is_some() { "multi" } else { "single" },
+            "threads_used": threads_used,
+            "chunks": chunks,
+        });
+        println!("{}", summary);
+        return Ok(());
+    }
+
+    status!("Dry run: {}", display_url(url));
+    if info.final_url != url {
+        status!("  Final URL: {}", display_url(&info.final_url));
+    }
+    match info.total_size {
+        Some(size) => status!("  Size: {} bytes", size),
+        None => status!("  Size: unknown"),
+    }
+    status!("  Range requests: {}", if info.supports_ranges { "supported" } else { "not supported" });
+    status!("  Output: {}", path);
+    match ranges {
+        Some(ranges) => {
+            if args.options.segment_size.is_some() {
+                status!("  Mode: multi-threaded ({} threads, {} segments)", threads_used, ranges.len());
+            } else {
+                status!("  Mode: multi-threaded ({} threads)", threads_used);
+            }
+            for (i, (start, end)) in ranges.iter().enumerate() {
+                status!("    Thread {}: bytes {}-{} ({} bytes)", i, start, end, end - start + 1);
+            }
+        }
+        None => status!("  Mode: single-threaded"),
+    }
+    Ok(())
+}
+
+/// Read URLs from a list file, or stdin for `-`, one per line, skipping
+/// blanks and `#` comments
+async fn read_url_list(path: &str) -> anyhow::Result<Vec<String>> {
+    let content = if path == "-" {
+        let mut content = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut tokio::io::stdin(), &mut content)
+            .await
+            .map_err(|e| anyhow!("Failed to read URLs from stdin: {}", e))?;
+        content
+    } else {
+        tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| anyhow!("Failed to read input file {}: {}", path, e))?
+    };
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| normalize_url(line).map_err(|e| anyhow!("{} line {}: {}", path, i + 1, e)))
+        .collect()
+}
+
+/// The URL piped in when none was given on the command line, e.g.
+/// `echo "$URL" | rget`. On a terminal this shows the usage instead
+fn url_from_stdin() -> anyhow::Result<String> {
+    use std::io::IsTerminal;
+
+    if std::io::stdin().is_terminal() {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "no URL given, pass a URL or --input-file, or pipe a URL to stdin",
+            )
+            .exit();
+    }
+    let content =
+        std::io::read_to_string(std::io::stdin()).map_err(|e| anyhow!("Failed to read the URL from stdin: {}", e))?;
+    let mut lines = content.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#'));
+    let url = lines.next().ok_or_else(|| anyhow!("No URL given and none on stdin"))?;
+    // 多个URL时要用--input-file -，免得一次只下载了第一个却没察觉
+    if lines.next().is_some() {
+        return Err(anyhow!("More than one URL on stdin, use --input-file - to download a list"));
+    }
+    Ok(url.to_string())
+}
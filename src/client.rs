@@ -0,0 +1,314 @@
+use crate::options::Options;
+use crate::cookies::Cookies;
+use crate::output::{self, verbose, warning};
+use base64::Engine;
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+/// Build the one HTTP client shared by the HEAD probe and every download request.
+/// `urls` are the URLs the user named; `--cookie` pairs go only to their sites
+/// and those of `--mirror`, not to wherever they redirect
+pub fn build_client(args: &Options, urls: &[String]) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::limited(args.max_redirect));
+
+    // 分块下载的Range和Content-Length都是按原始字节算的，默认不让服务器压缩，也不解压
+    if !args.compression {
+        builder = builder.no_gzip().no_brotli().no_deflate();
+    }
+
+    // 分块请求都发往同一个主机，空闲连接留在池里给下一个分块复用
+    if let Some(max) = args.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+    if let Some(secs) = args.pool_idle_timeout {
+        builder = builder.pool_idle_timeout(Duration::from_secs(secs));
+    }
+
+    // 很多小分块时Nagle算法会拖慢每个请求，reqwest默认就关掉了
+    builder = builder.tcp_nodelay(!args.no_tcp_nodelay);
+    if let Some(secs) = args.tcp_keepalive {
+        builder = builder.tcp_keepalive(Duration::from_secs(secs));
+    }
+
+    // 默认HTTPS通过ALPN协商版本，明文HTTP用HTTP/1.1
+    if args.http1_1 {
+        builder = builder.http1_only();
+    } else if args.http2 {
+        builder = builder.http2_prior_knowledge();
+    }
+
+    if let Some(secs) = args.timeout {
+        builder = builder.timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = args.connect_timeout {
+        builder = builder.connect_timeout(Duration::from_secs(secs));
+    }
+
+    // 绑定到某一族的地址后，hyper只会连接这一族的解析结果
+    if let Some(local) = local_address(args)? {
+        builder = builder.local_address(local);
+    }
+
+    if args.insecure {
+        warning!("Warning: TLS certificate verification is disabled (--insecure), the server's identity is not checked");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    if let Some(ref file) = args.ca_cert {
+        for cert in load_ca_certs(file)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if let Some(ref cert) = args.cert {
+        builder = builder.identity(load_identity(cert, args.key.as_deref(), args.cert_password.as_deref())?);
+    }
+
+    // 指定了--proxy时reqwest不再读取环境变量中的代理
+    if let Some(ref proxy) = args.proxy {
+        let proxy = reqwest::Proxy::all(proxy)
+            .map_err(|e| anyhow::anyhow!("Invalid proxy {}: {}", proxy, e))?;
+        builder = builder.proxy(proxy);
+    }
+    // 所有连接都走这个socket，URL里的主机名不再解析，只用在Host头里
+    if let Some(ref path) = args.unix_socket {
+        builder = unix_socket(builder, path)?;
+    }
+
+    // 重定向时服务器设置的cookie会自动带到下一个请求，每个分块请求也都会带上
+    let sites: Vec<Url> = urls.iter().chain(&args.mirrors).filter_map(|u| Url::parse(u).ok()).collect();
+    let cookies = Cookies::new(&args.cookies, &sites);
+    if let Some(ref file) = args.load_cookies {
+        let loaded = cookies.load_netscape_file(file)?;
+        verbose!("Loaded {} cookies from {}", loaded, file);
+    }
+    builder = builder.cookie_provider(Arc::new(cookies));
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::USER_AGENT, HeaderValue::from_str(&args.user_agent)?);
+    if !args.compression {
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+    }
+    if let Some(ref referer) = args.referer {
+        let value = HeaderValue::from_str(referer).map_err(|_| anyhow::anyhow!("Invalid referer: {}", referer))?;
+        headers.insert(header::REFERER, value);
+    }
+    // 和wget一样，--post-data默认按表单提交
+    if args.post_data.is_some() || args.post_file.is_some() {
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/x-www-form-urlencoded"));
+    }
+    if let Some(ref user) = args.user {
+        headers.insert(header::AUTHORIZATION, basic_auth_header(user, args.password.as_deref())?);
+    }
+
+    // --header里出现的名字替换掉上面的默认值，同名的多个--header都会发送
+    let mut overridden = Vec::new();
+    for (name, value) in &args.headers {
+        if !overridden.contains(name) {
+            headers.remove(name);
+            overridden.push(name.clone());
+        }
+        headers.append(name.clone(), value.clone());
+    }
+
+    // 这些头由client统一添加，单独的请求日志里看不到，所以在这里打印一次
+    verbose!("Headers sent with every request:");
+    log_headers(">", &headers);
+    builder = builder.default_headers(headers);
+
+    Ok(builder.build()?)
+}
+
+/// Send every connection of `builder` to the Unix socket at `path`, checked
+/// to be one so a wrong path fails before the first request
+#[cfg(unix)]
+fn unix_socket(builder: reqwest::ClientBuilder, path: &str) -> anyhow::Result<reqwest::ClientBuilder> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = std::fs::metadata(path).map_err(|e| anyhow::anyhow!("Can't use --unix-socket {}: {}", path, e))?;
+    if !metadata.file_type().is_socket() {
+        return Err(anyhow::anyhow!("--unix-socket {} is not a socket", path));
+    }
+    verbose!("Connecting through the Unix socket {}", path);
+    Ok(builder.unix_socket(path))
+}
+
+#[cfg(not(unix))]
+fn unix_socket(_builder: reqwest::ClientBuilder, _path: &str) -> anyhow::Result<reqwest::ClientBuilder> {
+    Err(anyhow::anyhow!("--unix-socket isn't supported on this platform"))
+}
+
+/// The address to bind outgoing connections to: `--bind-address`, or the
+/// unspecified address of the family `-4`/`-6` asks for. Checked up front so
+/// an address that doesn't belong to this machine fails before any request
+pub fn local_address(args: &Options) -> anyhow::Result<Option<IpAddr>> {
+    let Some(bind) = args.bind_address else {
+        return Ok(IpFamily::from_args(args).map(IpFamily::unspecified));
+    };
+    if let Some(family) = IpFamily::from_args(args)
+        && !family.matches(bind)
+    {
+        return Err(anyhow::anyhow!("--bind-address {} is not an {} address", bind, family));
+    }
+    std::net::TcpListener::bind((bind, 0)).map_err(|e| anyhow::anyhow!("Can't bind to {}: {}", bind, e))?;
+    Ok(Some(bind))
+}
+
+/// Address family forced by `-4`/`--inet4-only` or `-6`/`--inet6-only`,
+/// or implied by `--bind-address`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub fn from_args(args: &Options) -> Option<Self> {
+        if args.inet4_only {
+            Some(IpFamily::V4)
+        } else if args.inet6_only {
+            Some(IpFamily::V6)
+        } else {
+            args.bind_address.map(|addr| if addr.is_ipv4() { IpFamily::V4 } else { IpFamily::V6 })
+        }
+    }
+
+    fn unspecified(self) -> IpAddr {
+        match self {
+            IpFamily::V4 => Ipv4Addr::UNSPECIFIED.into(),
+            IpFamily::V6 => Ipv6Addr::UNSPECIFIED.into(),
+        }
+    }
+
+    pub fn matches(self, addr: IpAddr) -> bool {
+        match self {
+            IpFamily::V4 => addr.is_ipv4(),
+            IpFamily::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+impl std::fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpFamily::V4 => write!(f, "IPv4"),
+            IpFamily::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Resolve `host:port` and keep only the addresses of `family`, if one is forced
+pub async fn lookup(host: &str, port: u16, family: Option<IpFamily>) -> anyhow::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| anyhow::anyhow!("Could not resolve {}: {}", host, e))?
+        .filter(|addr| family.is_none_or(|family| family.matches(addr.ip())))
+        .collect();
+    match family {
+        Some(family) if addrs.is_empty() => Err(anyhow::anyhow!("{} has no {} address", host, family)),
+        _ if addrs.is_empty() => Err(anyhow::anyhow!("Could not resolve {}", host)),
+        _ => Ok(addrs),
+    }
+}
+
+/// Read the certificates in a `--ca-cert` file, either a PEM bundle or a single DER certificate
+fn load_ca_certs(file: &str) -> anyhow::Result<Vec<reqwest::Certificate>> {
+    let data = std::fs::read(file).map_err(|e| anyhow::anyhow!("Failed to read CA certificate {}: {}", file, e))?;
+    match reqwest::Certificate::from_pem_bundle(&data) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => {
+            let cert = reqwest::Certificate::from_der(&data)
+                .map_err(|_| anyhow::anyhow!("{} is not a PEM or DER certificate", file))?;
+            Ok(vec![cert])
+        }
+    }
+}
+
+/// Build the client identity for `--cert`: a PEM certificate with its PKCS#8
+/// PEM `key`, or a PKCS#12 archive (`.p12`/`.pfx`) holding both
+fn load_identity(cert: &str, key: Option<&str>, password: Option<&str>) -> anyhow::Result<reqwest::Identity> {
+    let read = |file: &str, what: &str| {
+        std::fs::read(file).map_err(|e| anyhow::anyhow!("Failed to read client {} {}: {}", what, file, e))
+    };
+    let cert_data = read(cert, "certificate")?;
+
+    match key {
+        Some(key) => {
+            let key_data = read(key, "key")?;
+            reqwest::Identity::from_pkcs8_pem(&cert_data, &key_data).map_err(|e| {
+                anyhow::anyhow!(
+                    "Failed to load client certificate {} with key {} (the key must be an unencrypted PKCS#8 PEM): {}",
+                    cert, key, e
+                )
+            })
+        }
+        // PEM证书没有--key时无从得到私钥
+        None if cert_data.starts_with(b"-----BEGIN") => Err(anyhow::anyhow!(
+            "{} is a PEM certificate, pass its private key with --key",
+            cert
+        )),
+        None => reqwest::Identity::from_pkcs12_der(&cert_data, password.unwrap_or(""))
+            .map_err(|e| anyhow::anyhow!("Failed to load PKCS#12 client certificate {} (wrong --cert-password?): {}", cert, e)),
+    }
+}
+
+/// Parse a `--method` argument such as `POST`
+pub fn parse_method(value: &str) -> Result<reqwest::Method, String> {
+    reqwest::Method::from_bytes(value.to_ascii_uppercase().as_bytes())
+        .map_err(|_| format!("invalid HTTP method '{}'", value))
+}
+
+/// Parse a `--header` argument of the form `Name: Value`
+pub fn parse_header(value: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = value
+        .split_once(':')
+        .ok_or_else(|| format!("invalid header '{}', expected 'Name: Value'", value))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("invalid header name '{}'", name.trim()))?;
+    let mut value = HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("invalid value for header '{}'", name))?;
+
+    // 凭据类的头不打印到日志里
+    if name == header::AUTHORIZATION || name == header::PROXY_AUTHORIZATION || name == header::COOKIE {
+        value.set_sensitive(true);
+    }
+    Ok((name, value))
+}
+
+fn basic_auth_header(user: &str, password: Option<&str>) -> anyhow::Result<HeaderValue> {
+    let credentials = format!("{}:{}", user, password.unwrap_or(""));
+    let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+    let mut value = HeaderValue::from_str(&format!("Basic {}", encoded))?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+/// Send a request, logging both directions in `--verbose` mode
+pub async fn send(request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+    let (client, request) = request.build_split();
+    let request = request?;
+
+    verbose!("> {} {}", request.method(), crate::display_url(request.url().as_str()));
+    log_headers(">", request.headers());
+
+    let response = client.execute(request).await?;
+
+    verbose!("< {:?} {}", response.version(), response.status());
+    log_headers("<", response.headers());
+    Ok(response)
+}
+
+fn log_headers(prefix: &str, headers: &HeaderMap) {
+    if output::verbosity() < output::Verbosity::Verbose {
+        return;
+    }
+    for (name, value) in headers {
+        if value.is_sensitive() {
+            eprintln!("{} {}: <redacted>", prefix, name);
+        } else {
+            eprintln!("{} {}: {}", prefix, name, value.to_str().unwrap_or("<binary>"));
+        }
+    }
+}
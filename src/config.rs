@@ -0,0 +1,378 @@
+use crate::cli::Cli;
+use crate::{client, cookies, hash, units};
+use anyhow::anyhow;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// Defaults read from `~/.rgetrc` or `--config`. Keys are the long option
+/// names, e.g. `user-agent = "..."` or `header = ["Accept: text/html"]`, and
+/// anything given on the command line wins, also over keys it conflicts with.
+///
+/// Every option about how to download can be set here. Left out are the ones
+/// that say what to download and where, or only make sense for a single run:
+/// the URL, `--input-file`, `--savepath`, `--mirror`, `--unix-socket`,
+/// `--range`, `--method`, `--post-data`, `--post-file`, `--hash`,
+/// `--checksum-file`, `--manifest`, `--expect-type`, `--continue`, `--force`,
+/// `--if-modified-since`, `--spider`, `--dry-run`, `--ask-password`, `--json`,
+/// and `--config` and `--no-config` themselves
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    output_dir: Option<String>,
+    print_hash: Option<String>,
+    /// `0` picks the count automatically, like `--threads auto`
+    threads: Option<u8>,
+    adaptive_threads: Option<bool>,
+    max_concurrent: Option<usize>,
+    segment_size: Option<String>,
+    max_range_size: Option<String>,
+    no_range_probe: Option<bool>,
+    prefer_single_thread_below: Option<String>,
+    verify_resume: Option<bool>,
+    keep_partial: Option<bool>,
+    no_part_file: Option<bool>,
+    content_on_error: Option<bool>,
+    timestamping: Option<bool>,
+    no_cache: Option<bool>,
+    unique_names: Option<bool>,
+    spread_mirrors: Option<bool>,
+    strict: Option<bool>,
+    quota: Option<String>,
+    retries: Option<u32>,
+    retry_delay: Option<u64>,
+    retry_max_delay: Option<u64>,
+    retry_on_http: Vec<u16>,
+    deterministic: Option<bool>,
+    wait: Option<u64>,
+    random_wait: Option<bool>,
+    limit_rate: Option<String>,
+    timeout: Option<u64>,
+    connect_timeout: Option<u64>,
+    max_filesize: Option<String>,
+    overall_timeout: Option<u64>,
+    user_agent: Option<String>,
+    referer: Option<String>,
+    compression: Option<bool>,
+    header: Vec<String>,
+    user: Option<String>,
+    password: Option<String>,
+    progress: Option<String>,
+    progress_template: Option<String>,
+    progress_file: Option<String>,
+    log_file: Option<String>,
+    speed_window: Option<u64>,
+    no_progress: Option<bool>,
+    quiet: Option<bool>,
+    /// Like giving `-v` this many times
+    verbose: Option<u8>,
+    stats: Option<bool>,
+    metrics_file: Option<String>,
+    max_redirect: Option<usize>,
+    pool_max_idle_per_host: Option<usize>,
+    pool_idle_timeout: Option<u64>,
+    /// `false` is the same as `--no-tcp-nodelay`
+    tcp_nodelay: Option<bool>,
+    tcp_keepalive: Option<u64>,
+    /// Written `"http1.1" = true`, since a bare dotted key makes a table
+    #[serde(rename = "http1.1")]
+    http1_1: Option<bool>,
+    http2: Option<bool>,
+    inet4_only: Option<bool>,
+    inet6_only: Option<bool>,
+    insecure: Option<bool>,
+    ca_cert: Option<String>,
+    cert: Option<String>,
+    key: Option<String>,
+    cert_password: Option<String>,
+    bind_address: Option<std::net::IpAddr>,
+    proxy: Option<String>,
+    cookie: Vec<String>,
+    load_cookies: Option<String>,
+    /// Keys set in the file, except those set to `false`
+    #[serde(skip)]
+    keys: Vec<String>,
+}
+
+/// The clap id of the option a config key sets
+fn arg_id(key: &str) -> String {
+    match key {
+        "header" => "headers".to_string(),
+        "cookie" => "cookies".to_string(),
+        "http1.1" => "http1_1".to_string(),
+        _ => key.replace('-', "_"),
+    }
+}
+
+/// Every pair of options clap won't take together, whichever of the two declares it
+fn conflicting_pairs() -> Vec<(String, String)> {
+    let command = Cli::command();
+    let mut pairs = Vec::new();
+    for arg in command.get_arguments() {
+        for other in command.get_arg_conflicts_with(arg) {
+            pairs.push((arg.get_id().to_string(), other.get_id().to_string()));
+        }
+    }
+    pairs
+}
+
+/// The config file read when `--config` isn't given
+fn default_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".rgetrc"))
+}
+
+impl Config {
+    /// Read `path`, or `~/.rgetrc` if there is one. Only an explicit
+    /// `--config` that can't be read is an error
+    pub fn load(path: Option<&str>) -> anyhow::Result<Option<Config>> {
+        let (path, explicit) = match path {
+            Some(path) => (PathBuf::from(path), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(None),
+            },
+        };
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if !explicit && e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(anyhow!("Failed to read config file {}: {}", path.display(), e)),
+        };
+        let config = Config::parse(&content).map_err(|e| anyhow!("Invalid config file {}: {}", path.display(), e))?;
+        Ok(Some(config))
+    }
+
+    fn parse(content: &str) -> Result<Config, toml::de::Error> {
+        let table: toml::Table = toml::from_str(content)?;
+        let keys = table
+            .iter()
+            .filter(|(_, value)| value.as_bool() != Some(false))
+            .map(|(key, _)| key.clone())
+            .collect();
+        Ok(Config { keys, ..table.try_into()? })
+    }
+
+    /// Fill in every option of `args` that wasn't given on the command line
+    pub fn apply(self, args: &mut Cli, matches: &ArgMatches) -> anyhow::Result<()> {
+        let on_command_line = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        // clap只检查了命令行，和命令行上的选项冲突的配置项直接忽略
+        let pairs = conflicting_pairs();
+        let unset = |id: &str| {
+            !on_command_line(id)
+                && !pairs.iter().any(|(a, b)| (a == id && on_command_line(b)) || (b == id && on_command_line(a)))
+        };
+        for (i, a) in self.keys.iter().enumerate() {
+            for b in &self.keys[i + 1..] {
+                let (id_a, id_b) = (arg_id(a), arg_id(b));
+                let conflict = pairs.iter().any(|(x, y)| (*x == id_a && *y == id_b) || (*x == id_b && *y == id_a));
+                if conflict && unset(&id_a) && unset(&id_b) {
+                    return Err(anyhow!("{} and {} in config can't both be set", a, b));
+                }
+            }
+        }
+
+        // 类型和Cli里相同的字段直接覆盖
+        macro_rules! merge {
+            ($target:expr => $($field:ident),* $(,)?) => {
+                $(
+                    if let Some(value) = self.$field
+                        && unset(stringify!($field))
+                    {
+                        $target.$field = value;
+                    }
+                )*
+            };
+        }
+        merge!(args => strict, random_wait, speed_window, no_progress, quiet, verbose);
+        merge!(
+            args.options => threads, adaptive_threads, no_range_probe, verify_resume, keep_partial, no_part_file,
+            content_on_error, timestamping, no_cache, unique_names, spread_mirrors, retries, retry_delay,
+            retry_max_delay, deterministic, compression, stats, max_redirect, http1_1, http2, inet4_only, inet6_only,
+            insecure,
+        );
+        macro_rules! merge_option {
+            ($target:expr => $($field:ident),* $(,)?) => {
+                $(
+                    if self.$field.is_some() && unset(stringify!($field)) {
+                        $target.$field = self.$field;
+                    }
+                )*
+            };
+        }
+        merge_option!(args => wait, progress_template, progress_file, log_file, metrics_file);
+        merge_option!(
+            args.options => output_dir, max_concurrent, pool_max_idle_per_host, pool_idle_timeout, tcp_keepalive, timeout,
+            connect_timeout, overall_timeout, referer, user, password, ca_cert, cert, key, cert_password, bind_address,
+            proxy, load_cookies,
+        );
+
+        if let Some(user_agent) = self.user_agent
+            && unset("user_agent")
+        {
+            args.options.user_agent = user_agent;
+        }
+        if let Some(ref value) = self.limit_rate
+            && unset("limit_rate")
+        {
+            args.options.limit_rate = Some(units::parse_size(value).map_err(|e| anyhow!("limit-rate in config: {}", e))?);
+        }
+        if let Some(ref value) = self.segment_size
+            && unset("segment_size")
+        {
+            args.options.segment_size = Some(crate::options::parse_segment_size(value).map_err(|e| anyhow!("segment-size in config: {}", e))?);
+        }
+        if let Some(ref value) = self.max_range_size
+            && unset("max_range_size")
+        {
+            args.options.max_range_size = Some(
+                crate::options::parse_max_range_size(value).map_err(|e| anyhow!("max-range-size in config: {}", e))?,
+            );
+        }
+        if let Some(ref value) = self.prefer_single_thread_below
+            && unset("prefer_single_thread_below")
+        {
+            args.options.prefer_single_thread_below = Some(
+                units::parse_size(value).map_err(|e| anyhow!("prefer-single-thread-below in config: {}", e))?,
+            );
+        }
+        if let Some(ref value) = self.max_filesize
+            && unset("max_filesize")
+        {
+            args.options.max_filesize = Some(units::parse_size(value).map_err(|e| anyhow!("max-filesize in config: {}", e))?);
+        }
+        if let Some(ref value) = self.quota
+            && unset("quota")
+        {
+            args.quota = Some(units::parse_size(value).map_err(|e| anyhow!("quota in config: {}", e))?);
+        }
+        if let Some(ref value) = self.print_hash
+            && unset("print_hash")
+        {
+            args.options.print_hash =
+                Some(hash::parse_algorithm(value).map_err(|e| anyhow!("print-hash in config: {}", e))?);
+        }
+        if let Some(ref value) = self.progress
+            && unset("progress")
+        {
+            args.progress = clap::ValueEnum::from_str(value, true)
+                .map_err(|e| anyhow!("progress in config: {}", e))?;
+        }
+
+        if let Some(nodelay) = self.tcp_nodelay
+            && unset("tcp_nodelay")
+            && unset("no_tcp_nodelay")
+        {
+            args.options.no_tcp_nodelay = !nodelay;
+        }
+
+        if args.options.retry_on_http.is_empty() {
+            args.options.retry_on_http = self.retry_on_http;
+        }
+
+        // 命令行上的同名头替换掉配置文件里的
+        let mut headers = Vec::new();
+        for value in &self.header {
+            let (name, value) = client::parse_header(value).map_err(|e| anyhow!("header in config: {}", e))?;
+            if !args.options.headers.iter().any(|(n, _)| *n == name) {
+                headers.push((name, value));
+            }
+        }
+        headers.append(&mut args.options.headers);
+        args.options.headers = headers;
+
+        // 命令行上的同名cookie也一样
+        let mut cookies = Vec::new();
+        for value in self.cookie {
+            let value = cookies::parse_cookie(&value).map_err(|e| anyhow!("cookie in config: {}", e))?;
+            let name = value.split_once('=').map(|(name, _)| name.trim()).unwrap_or_default();
+            if !args.options.cookies.iter().any(|c| c.split_once('=').is_some_and(|(n, _)| n.trim() == name)) {
+                cookies.push(value);
+            }
+        }
+        cookies.append(&mut args.options.cookies);
+        args.options.cookies = cookies;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::FromArgMatches;
+
+    /// The options listed as left out in the docs of `Config`
+    const COMMAND_LINE_ONLY: &[&str] = &[
+        "url", "input_file", "savepath", "mirrors", "unix_socket", "range", "method", "post_data", "post_file", "hash",
+        "checksum_file", "manifest", "expect_type", "resume", "force", "if_modified_since", "spider", "dry_run",
+        "ask_password", "json", "config", "no_config",
+    ];
+
+    /// Apply `content` to the parsed command line `line`
+    fn apply(content: &str, line: &[&str]) -> anyhow::Result<Cli> {
+        let matches = Cli::command().try_get_matches_from(line).unwrap();
+        let mut args = Cli::from_arg_matches(&matches).unwrap();
+        Config::parse(content).unwrap().apply(&mut args, &matches)?;
+        Ok(args)
+    }
+
+    #[test]
+    fn covers_every_option() {
+        for arg in Cli::command().get_arguments() {
+            let id = arg.get_id().as_str();
+            if COMMAND_LINE_ONLY.contains(&id) || id == "no_tcp_nodelay" {
+                continue;
+            }
+            let key = match id {
+                "headers" => "header".to_string(),
+                "cookies" => "cookie".to_string(),
+                "http1_1" => "http1.1".to_string(),
+                _ => id.replace('_', "-"),
+            };
+            assert_eq!(arg_id(&key), id);
+            // 类型不对的报错说明这个键存在，未知的键报的是unknown field
+            let error = toml::from_str::<Config>(&format!("\"{}\" = {{}}", key)).unwrap_err().to_string();
+            let long = arg.get_long().unwrap_or(id);
+            assert!(!error.contains("unknown field"), "--{} can't be set in the config file", long);
+        }
+    }
+
+    #[test]
+    fn fills_in_new_options() {
+        let content = r#"
+            no-cache = true
+            max-range-size = "1M"
+            quota = "10M"
+            print-hash = "sha1"
+            verbose = 2
+            cookie = ["a=1", "b=2"]
+        "#;
+        let args = apply(content, &["rget", "--cookie", "b=3", "http://x/"]).unwrap();
+        assert!(args.options.no_cache);
+        assert_eq!(args.options.max_range_size, Some(1024 * 1024));
+        assert_eq!(args.quota, Some(10 * 1024 * 1024));
+        assert_eq!(args.options.print_hash, Some(hash::HashAlgorithm::Sha1));
+        assert_eq!(args.verbose, 2);
+        assert_eq!(args.options.cookies, ["a=1", "b=3"]);
+    }
+
+    #[test]
+    fn command_line_wins_over_conflicting_keys() {
+        let args = apply("quiet = true\ninsecure = true", &["rget", "-v", "--ca-cert", "ca.pem", "http://x/"]).unwrap();
+        assert!(!args.quiet && !args.options.insecure);
+        let args = apply("unique-names = true\nno-range-probe = true", &["rget", "-f", "-N", "http://x/"]).unwrap();
+        assert!(!args.options.unique_names && !args.options.no_range_probe);
+        let args = apply("output-dir = \"dl\"", &["rget", "-s", "out.bin", "http://x/"]).unwrap();
+        assert_eq!(args.options.output_dir, None);
+    }
+
+    #[test]
+    fn rejects_conflicting_keys() {
+        let error = apply("\"http1.1\" = true\nhttp2 = true", &["rget", "http://x/"]).err().unwrap();
+        assert_eq!(error.to_string(), "http1.1 and http2 in config can't both be set");
+        assert!(apply("keep-partial = true\ncontent-on-error = true", &["rget", "http://x/"]).is_err());
+        // 命令行给了其中一个时以命令行为准
+        let args = apply("\"http1.1\" = true\nhttp2 = true", &["rget", "--http2", "http://x/"]).unwrap();
+        assert!(args.options.http2 && !args.options.http1_1);
+    }
+}
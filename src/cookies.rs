@@ -0,0 +1,101 @@
+use anyhow::anyhow;
+use reqwest::cookie::{CookieStore, Jar};
+use reqwest::header::HeaderValue;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// Cookie store for the shared client: a jar that follows `Set-Cookie` on
+/// redirects, plus the `--cookie` pairs that go to every URL on the sites
+/// the user named
+pub struct Cookies {
+    jar: Jar,
+    always: Vec<String>,
+    sites: Vec<url::Origin>,
+}
+
+impl Cookies {
+    pub fn new(always: &[String], sites: &[Url]) -> Self {
+        Cookies {
+            jar: Jar::default(),
+            always: always.iter().map(|c| c.trim().to_string()).collect(),
+            sites: sites.iter().map(Url::origin).collect(),
+        }
+    }
+
+    /// Load a Netscape/Mozilla `cookies.txt` file as written by curl, wget and browser extensions
+    pub fn load_netscape_file(&self, file: &str) -> anyhow::Result<usize> {
+        let content = std::fs::read_to_string(file)
+            .map_err(|e| anyhow!("Failed to read cookie file {}: {}", file, e))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut loaded = 0;
+        for (n, line) in content.lines().enumerate() {
+            // #HttpOnly_前缀标记的是普通的cookie行，其余#开头的是注释
+            let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [domain, include_subdomains, path, secure, expires, name, value] = fields[..] else {
+                return Err(anyhow!("{}:{}: expected 7 tab-separated fields", file, n + 1));
+            };
+            let expires: u64 = expires
+                .parse()
+                .map_err(|_| anyhow!("{}:{}: invalid expiry time '{}'", file, n + 1, expires))?;
+            // 0表示会话cookie
+            if expires != 0 && expires < now {
+                continue;
+            }
+
+            let secure = secure.eq_ignore_ascii_case("TRUE");
+            let host = domain.trim_start_matches('.');
+            let url = Url::parse(&format!("{}://{}{}", if secure { "https" } else { "http" }, host, path))
+                .map_err(|e| anyhow!("{}:{}: invalid domain or path: {}", file, n + 1, e))?;
+
+            let mut cookie = format!("{}={}; Path={}", name, value, path);
+            // 不带Domain属性的cookie只发给这一个主机
+            if include_subdomains.eq_ignore_ascii_case("TRUE") {
+                cookie.push_str(&format!("; Domain={}", host));
+            }
+            if secure {
+                cookie.push_str("; Secure");
+            }
+            self.jar.add_cookie_str(&cookie, &url);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+}
+
+impl CookieStore for Cookies {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        self.jar.set_cookies(cookie_headers, url);
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        // 重定向到别的站点时不带--cookie，和Authorization一样
+        let mut pairs: Vec<String> = if self.sites.contains(&url.origin()) { self.always.clone() } else { Vec::new() };
+        if let Some(stored) = self.jar.cookies(url).and_then(|v| v.to_str().ok().map(|s| s.to_string())) {
+            pairs.push(stored);
+        }
+        if pairs.is_empty() {
+            return None;
+        }
+
+        let mut value = HeaderValue::from_str(&pairs.join("; ")).ok()?;
+        value.set_sensitive(true);
+        Some(value)
+    }
+}
+
+/// Check a `--cookie` argument has the `name=value` form
+pub fn parse_cookie(value: &str) -> Result<String, String> {
+    match value.split_once('=') {
+        Some((name, _)) if !name.trim().is_empty() && HeaderValue::from_str(value).is_ok() => Ok(value.to_string()),
+        _ => Err(format!("invalid cookie '{}', expected 'name=value'", value)),
+    }
+}
@@ -0,0 +1,160 @@
+use crate::options::Options;
+use crate::{DownloadOutcome, client, hash};
+use anyhow::anyhow;
+use std::time::Duration;
+
+/// Download one URL from Rust code, with the same defaults as the `rget`
+/// command. Each method sets the flag of the same name; anything not set
+/// keeps the command's default, except that nothing is printed: the status
+/// lines and progress bars belong to the `rget` command.
+///
+/// Each download builds its own options and HTTP client, so several can run
+/// at once. Some state is still process-wide: the verbosity, progress and
+/// log file settings and the per-thread bars kept for `--progress-file`,
+/// which only [`run`](crate::run) sets, and the Ctrl-C flag that stops every
+/// running download once `run` has installed its handler. A program calling
+/// both shares them between its `Downloader`s and the command
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// # async fn example() -> anyhow::Result<()> {
+/// let outcome = rget::Downloader::new("https://example.com/archive.tar.gz")
+///     .output_dir("downloads")
+///     .threads(4)
+///     .timeout(Duration::from_secs(30))
+///     .retries(5)
+///     .hash("sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08")
+///     .download()
+///     .await?;
+/// assert_eq!(outcome.hash_status(), rget::HashStatus::Verified);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Downloader {
+    url: String,
+    options: Options,
+    /// `Name: Value` lines, checked when the download starts
+    headers: Vec<String>,
+}
+
+/// Whole seconds for a timeout flag, rounded up so a short timeout isn't zero
+fn timeout_secs(timeout: Duration) -> u64 {
+    timeout.as_millis().div_ceil(1000) as u64
+}
+
+impl Downloader {
+    /// A download of `url`, saved under the name from the URL or server in
+    /// the current directory
+    pub fn new(url: impl Into<String>) -> Self {
+        Downloader {
+            url: url.into(),
+            options: Options::default(),
+            headers: Vec::new(),
+        }
+    }
+
+    /// The path to save the file to, like `--savepath`. A directory, or a path
+    /// ending in a separator, keeps the name from the URL or server
+    pub fn savepath(mut self, path: impl Into<String>) -> Self {
+        self.options.savepath = Some(path.into());
+        self
+    }
+
+    /// Directory to save into, created if missing, like `--output-dir`
+    pub fn output_dir(mut self, dir: impl Into<String>) -> Self {
+        self.options.output_dir = Some(dir.into());
+        self
+    }
+
+    /// Number of threads, like `--threads`. `0` picks the count from the CPUs
+    pub fn threads(mut self, threads: u8) -> Self {
+        self.options.threads = threads;
+        self
+    }
+
+    /// Timeout for each request, like `--timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = Some(timeout_secs(timeout));
+        self
+    }
+
+    /// Timeout for establishing a connection, like `--connect-timeout`
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.options.connect_timeout = Some(timeout_secs(timeout));
+        self
+    }
+
+    /// Retries after a failed attempt, like `--retries`
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.options.retries = retries;
+        self
+    }
+
+    /// Limit the speed to this many bytes per second, like `--limit-rate`
+    pub fn limit_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.options.limit_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Refuse files larger than this many bytes, like `--max-filesize`
+    pub fn max_filesize(mut self, bytes: u64) -> Self {
+        self.options.max_filesize = Some(bytes);
+        self
+    }
+
+    /// Verify the file against `<hex>` or `<algorithm>:<hex>`, like `--hash`
+    pub fn hash(mut self, hash: impl Into<String>) -> Self {
+        self.options.hash = Some(hash.into());
+        self
+    }
+
+    /// Continue a partial download of the same file, like `--continue`
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.options.resume = resume;
+        self
+    }
+
+    /// The `User-Agent` to send, like `--user-agent`
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.options.user_agent = user_agent.into();
+        self
+    }
+
+    /// A `Name: Value` request header, like `--header`
+    pub fn header(mut self, header: impl Into<String>) -> Self {
+        self.headers.push(header.into());
+        self
+    }
+
+    /// Proxy for all requests, like `--proxy`
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.options.proxy = Some(proxy.into());
+        self
+    }
+
+    /// Don't check TLS certificates, like `--insecure`
+    pub fn insecure(mut self, insecure: bool) -> Self {
+        self.options.insecure = insecure;
+        self
+    }
+
+    /// Download and verify the file
+    pub async fn download(&self) -> anyhow::Result<DownloadOutcome> {
+        let mut args = self.options.clone();
+        let url = crate::normalize_url(&self.url)?;
+        for header in &self.headers {
+            args.headers.push(client::parse_header(header).map_err(|e| anyhow!("{}", e))?);
+        }
+        if args.threads == 0 {
+            args.threads = crate::auto_threads();
+        }
+        let expected_hash = args.hash.as_deref().map(hash::ExpectedHash::parse).transpose()?;
+
+        let client = client::build_client(&args, std::slice::from_ref(&url))?;
+        let output_dir = crate::paths::output_dir_prefix(&args);
+        let savepath = args.savepath.as_deref().or(output_dir.as_deref());
+        crate::run_download(&args, &client, &url, savepath, expected_hash.as_ref(), None).await
+    }
+}
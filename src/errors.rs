@@ -0,0 +1,327 @@
+use crate::options::Options;
+use crate::retry;
+use crate::retry::RetryPolicy;
+use crate::throttle::QuotaExceeded;
+use anyhow::anyhow;
+use futures::StreamExt;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// A ranged GET came back as `200 OK` with the whole body instead of `206`
+#[derive(Debug)]
+pub struct RangeIgnored;
+
+impl std::fmt::Display for RangeIgnored {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "server ignored the range request")
+    }
+}
+
+impl std::error::Error for RangeIgnored {}
+
+/// The server answered a conditional request with `304 Not Modified`
+#[derive(Debug)]
+pub struct NotModified;
+
+impl std::fmt::Display for NotModified {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "not modified on the server")
+    }
+}
+
+impl std::error::Error for NotModified {}
+
+/// `--overall-timeout` ran out before the download finished
+#[derive(Debug)]
+struct OverallTimeout(u64);
+
+impl std::fmt::Display for OverallTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "overall timeout exceeded ({}s)", self.0)
+    }
+}
+
+impl std::error::Error for OverallTimeout {}
+
+/// The file is larger than `--max-filesize`
+#[derive(Debug)]
+struct FileTooLarge {
+    limit: u64,
+    /// The size the server reported, if it did
+    size: Option<u64>,
+}
+
+impl std::fmt::Display for FileTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.size {
+            Some(size) => write!(f, "file is {} bytes, larger than --max-filesize {} bytes", size, self.limit),
+            None => write!(f, "download exceeded --max-filesize {} bytes, aborting", self.limit),
+        }
+    }
+}
+
+impl std::error::Error for FileTooLarge {}
+
+/// Fail with `FileTooLarge` if `size` bytes would exceed `--max-filesize`.
+/// `known` says whether `size` is the full file size or a running count
+pub fn check_filesize(limit: Option<u64>, size: u64, known: bool) -> anyhow::Result<()> {
+    match limit {
+        Some(limit) if size > limit => Err(FileTooLarge {
+            limit,
+            size: known.then_some(size),
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// The download was stopped with Ctrl-C
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Cancelled by the first Ctrl-C. Chunk tasks and the streaming loops watch
+/// it so they can flush what they have and save the resume state
+pub static INTERRUPT: std::sync::LazyLock<CancellationToken> = std::sync::LazyLock::new(CancellationToken::new);
+
+/// Resolve once Ctrl-C has been pressed
+pub async fn interrupted() {
+    INTERRUPT.cancelled().await
+}
+
+/// How long an interrupted download gets to flush its data and save its
+/// resume state before rget exits regardless
+pub const INTERRUPT_GRACE: Duration = Duration::from_secs(5);
+
+/// Run `fut`, giving up once `--overall-timeout` has passed since `started`
+/// or Ctrl-C is pressed. Dropping the future also aborts any chunk tasks it
+/// spawned, so on Ctrl-C it first gets `INTERRUPT_GRACE` to wind down itself
+pub async fn guard_download<T>(
+    args: &Options,
+    started: Instant,
+    fut: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let fut = async {
+        match args.overall_timeout {
+            Some(secs) => tokio::time::timeout_at(started + Duration::from_secs(secs), fut)
+                .await
+                .map_err(|_| anyhow::Error::from(OverallTimeout(secs)))?,
+            None => fut.await,
+        }
+    };
+    tokio::pin!(fut);
+
+    let result = tokio::select! {
+        result = &mut fut => result,
+        _ = interrupted() => {
+            let _ = tokio::time::timeout(INTERRUPT_GRACE, &mut fut).await;
+            return Err(Interrupted.into());
+        }
+    };
+    // 任务因为Ctrl-C取消时报告的错误不是真正的原因
+    match result {
+        Err(_) if INTERRUPT.is_cancelled() => Err(Interrupted.into()),
+        result => result,
+    }
+}
+
+/// Writing the output failed in a way that retrying won't fix, like a full disk
+#[derive(Debug)]
+struct LocalIoError {
+    path: String,
+    source: std::io::Error,
+}
+
+impl std::fmt::Display for LocalIoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.source.kind() {
+            std::io::ErrorKind::StorageFull => write!(f, "no space left on device: {}", self.path),
+            std::io::ErrorKind::PermissionDenied => write!(f, "permission denied: {}", self.path),
+            std::io::ErrorKind::ReadOnlyFilesystem => write!(f, "read-only file system: {}", self.path),
+            _ => write!(f, "{}: {}", self.path, self.source),
+        }
+    }
+}
+
+impl std::error::Error for LocalIoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// The server answered with an error status
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: reqwest::StatusCode,
+    /// From `Retry-After` on a 429 or 503
+    pub retry_after: Option<Duration>,
+    /// The response body, kept for `--content-on-error`
+    pub content: Option<Vec<u8>>,
+}
+
+impl HttpError {
+    fn from_response(response: &reqwest::Response) -> Self {
+        let status = response.status();
+        let retry_after = match status {
+            reqwest::StatusCode::TOO_MANY_REQUESTS | reqwest::StatusCode::SERVICE_UNAVAILABLE => response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_retry_after),
+            _ => None,
+        };
+        HttpError {
+            status,
+            retry_after,
+            content: None,
+        }
+    }
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP {}", self.status)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// How much of an error response's body goes into the error message
+const ERROR_BODY_LIMIT: usize = 4 * 1024;
+
+/// How long to wait for that much of the body; the status is already known
+const ERROR_BODY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest error page `--content-on-error` saves; the rest is cut off
+const ERROR_CONTENT_LIMIT: usize = 16 * 1024 * 1024;
+
+/// An `HttpError` for `response`, shown as `context: HTTP <status>` followed by
+/// the start of the response body as text, since servers often explain a 401
+/// or 403 there ("token expired"). With `keep_content` the whole body stays in
+/// the `HttpError` to be saved
+pub async fn http_error_with_body(
+    response: reqwest::Response,
+    context: impl std::fmt::Display,
+    keep_content: bool,
+) -> anyhow::Error {
+    let mut error = HttpError::from_response(&response);
+    let mut message = format!("{}: HTTP {}", context, error.status);
+    // 只为错误信息读的时候不必等太久，要保存时就读完整个响应
+    let body = if keep_content {
+        read_error_body(response, ERROR_CONTENT_LIMIT).await
+    } else {
+        tokio::time::timeout(ERROR_BODY_TIMEOUT, read_error_body(response, ERROR_BODY_LIMIT + 1))
+            .await
+            .unwrap_or_default()
+    };
+    if let Some(summary) = body_summary(&body) {
+        message = format!("{}: {}", message, summary);
+    }
+    if keep_content {
+        error.content = Some(body);
+    }
+    anyhow::Error::from(error).context(message)
+}
+
+/// Up to `limit` bytes of the body; a broken connection ends it early
+async fn read_error_body(response: reqwest::Response, limit: usize) -> Vec<u8> {
+    let mut stream = response.bytes_stream();
+    let mut body = Vec::new();
+    while body.len() < limit
+        && let Some(Ok(chunk)) = stream.next().await
+    {
+        body.extend_from_slice(&chunk);
+    }
+    body.truncate(limit);
+    body
+}
+
+/// The first `ERROR_BODY_LIMIT` bytes of `body` with whitespace squeezed onto
+/// one line, or `None` if there is no text
+fn body_summary(body: &[u8]) -> Option<String> {
+    let shown = &body[..body.len().min(ERROR_BODY_LIMIT)];
+    let text = String::from_utf8_lossy(shown).split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.is_empty() {
+        None
+    } else if body.len() > shown.len() {
+        Some(format!("{}...", text))
+    } else {
+        Some(text)
+    }
+}
+
+/// Parse `Retry-After`, which is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    // 已经过去的时间就是不用等
+    Some(date.duration_since(std::time::SystemTime::now()).unwrap_or_default())
+}
+
+/// Longest `Retry-After` rget is willing to wait for
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// How long to wait before retry `attempt` after `e`: what the server asked
+/// for in `Retry-After` if it did, otherwise the backoff. Also returns a note
+/// for the retry message saying which it was
+pub fn retry_delay(e: &anyhow::Error, backoff: &mut retry::Backoff, attempt: u32) -> (Duration, String) {
+    match e.downcast_ref::<HttpError>().and_then(|http| http.retry_after) {
+        Some(wait) if wait > MAX_RETRY_AFTER => (
+            MAX_RETRY_AFTER,
+            format!(
+                " (server asked to wait {}s, waiting {}s instead)",
+                wait.as_secs(),
+                MAX_RETRY_AFTER.as_secs()
+            ),
+        ),
+        Some(wait) => (wait, format!(" (waiting {}s as the server asked)", wait.as_secs())),
+        None => (backoff.delay_for(attempt), String::new()),
+    }
+}
+
+/// Whether retrying the same request could make `e` go away
+pub fn is_retryable(e: &anyhow::Error, retry: &RetryPolicy) -> bool {
+    if let Some(http) = e.downcast_ref::<HttpError>() {
+        return retry.http_statuses.contains(http.status.as_u16());
+    }
+    !is_fatal(e)
+}
+
+/// Errors that no retry or other mirror can fix, so the download stops
+pub fn is_fatal(e: &anyhow::Error) -> bool {
+    e.is::<OverallTimeout>()
+        || e.is::<LocalIoError>()
+        || e.is::<FileTooLarge>()
+        || e.is::<Interrupted>()
+        || e.is::<QuotaExceeded>()
+}
+
+/// Turn an error from opening or writing `path` into a `LocalIoError` when
+/// retrying can't help, otherwise prefix it with `context`
+pub fn file_error(e: std::io::Error, path: &str, context: impl std::fmt::Display) -> anyhow::Error {
+    match e.kind() {
+        std::io::ErrorKind::StorageFull
+        | std::io::ErrorKind::PermissionDenied
+        | std::io::ErrorKind::ReadOnlyFilesystem => LocalIoError {
+            path: path.to_string(),
+            source: e,
+        }
+        .into(),
+        _ => anyhow!("{}: {}", context, e),
+    }
+}
+
+pub fn is_range_refused(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<HttpError>()
+        .is_some_and(|e| e.status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE)
+}
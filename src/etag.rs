@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::sync::Mutex;
+
+/// Entries kept in the cache; the oldest are dropped first
+const MAX_ENTRIES: usize = 1000;
+
+/// The `ETag` a file had when it was saved to `path`
+#[derive(Debug, Deserialize, Serialize)]
+struct Entry {
+    url: String,
+    path: String,
+    etag: String,
+    /// Size of the saved file, to notice when it was changed locally
+    size: u64,
+}
+
+/// `ETag`s of earlier downloads, used to send `If-None-Match` when the same
+/// URL is saved to the same path again. Lives in `~/.cache/rget/etags.json`
+pub struct EtagCache {
+    file: PathBuf,
+    entries: Vec<Entry>,
+}
+
+fn cache_file() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(dir.join("rget").join("etags.json"))
+}
+
+/// Held while the cache file is rewritten, so parallel downloads don't drop
+/// each other's entries
+static SAVING: Mutex<()> = Mutex::const_new(());
+
+/// Remember `etag` for `url` saved at `path`, or forget the old one when the
+/// server didn't send an `ETag` this time
+pub async fn remember(url: &str, path: &str, etag: Option<&str>) -> anyhow::Result<()> {
+    let _saving = SAVING.lock().await;
+    let Some(mut cache) = EtagCache::load().await else {
+        return Ok(());
+    };
+    // 没有ETag也没有旧记录时不必改写文件
+    if cache.record_entry(url, path, etag).await? {
+        cache.save().await?;
+    }
+    Ok(())
+}
+
+/// Whether an `ETag` is cached for `url` saved at `path`
+pub async fn is_cached(url: &str, path: &str) -> bool {
+    match EtagCache::load().await {
+        Some(cache) => cache.lookup(url, path).await.is_some(),
+        None => false,
+    }
+}
+
+/// `path` made absolute, so runs from different directories agree on the key
+fn key_path(path: &str) -> String {
+    std::path::absolute(path)
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+impl EtagCache {
+    /// Read the cache. A missing or unreadable file is an empty cache; `None`
+    /// when there is no home directory to keep it in
+    pub async fn load() -> Option<Self> {
+        let file = cache_file()?;
+        let entries = match tokio::fs::read_to_string(&file).await {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
+            Err(_) => Vec::new(),
+        };
+        Some(EtagCache { file, entries })
+    }
+
+    /// The `ETag` recorded for `url` saved at `path`, if the file there still
+    /// has the size it had after that download
+    pub async fn lookup(&self, url: &str, path: &str) -> Option<&str> {
+        let key = key_path(path);
+        let entry = self.entries.iter().find(|e| e.url == url && e.path == key)?;
+        let size = tokio::fs::metadata(path).await.ok()?.len();
+        (size == entry.size).then_some(entry.etag.as_str())
+    }
+
+    /// Replace the entry for `url` at `path`, returning whether anything changed
+    async fn record_entry(&mut self, url: &str, path: &str, etag: Option<&str>) -> anyhow::Result<bool> {
+        let key = key_path(path);
+        let before = self.entries.len();
+        self.entries.retain(|e| !(e.url == url && e.path == key));
+        if etag.is_none() && self.entries.len() == before {
+            return Ok(false);
+        }
+        if let Some(etag) = etag {
+            let size = tokio::fs::metadata(path).await?.len();
+            self.entries.push(Entry {
+                url: url.to_string(),
+                path: key,
+                etag: etag.to_string(),
+                size,
+            });
+        }
+        if self.entries.len() > MAX_ENTRIES {
+            self.entries.drain(..self.entries.len() - MAX_ENTRIES);
+        }
+        Ok(true)
+    }
+
+    async fn save(&self) -> anyhow::Result<()> {
+        if let Some(dir) = self.file.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        // 几个rget同时运行时各写各的临时文件，重命名保证缓存不会只写了一半
+        let tmp_path = self.file.with_extension(format!("{}.tmp", std::process::id()));
+        tokio::fs::write(&tmp_path, serde_json::to_string(&self.entries)?).await?;
+        tokio::fs::rename(&tmp_path, &self.file).await?;
+        Ok(())
+    }
+}
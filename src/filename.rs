@@ -0,0 +1,231 @@
+use percent_encoding::percent_decode_str;
+
+/// Extract the file name from a `Content-Disposition` header value, preferring
+/// the RFC 5987 `filename*=` form over plain `filename=`
+pub fn from_content_disposition(value: &str) -> Option<String> {
+    let mut plain = None;
+    let mut extended = None;
+
+    for param in split_params(value) {
+        let Some((key, val)) = param.split_once('=') else {
+            continue;
+        };
+        match key.trim().to_ascii_lowercase().as_str() {
+            "filename*" => extended = decode_extended(val.trim()),
+            "filename" => plain = Some(unquote(val.trim())),
+            _ => {}
+        }
+    }
+
+    extended.or(plain).and_then(|name| sanitize_filename(&name))
+}
+
+// 按分号切分参数，但忽略引号里的分号
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                params.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    params.push(&value[start..]);
+    params
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => {
+            let mut out = String::new();
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                if c == '\\' {
+                    if let Some(next) = chars.next() {
+                        out.push(next);
+                    }
+                } else {
+                    out.push(c);
+                }
+            }
+            out
+        }
+        None => value.to_string(),
+    }
+}
+
+/// `UTF-8'lang'percent%20encoded` as defined by RFC 5987
+fn decode_extended(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let encoded = parts.next()?;
+
+    let bytes: Vec<u8> = percent_decode_str(encoded).collect();
+    if charset.eq_ignore_ascii_case("utf-8") {
+        String::from_utf8(bytes).ok()
+    } else {
+        // ISO-8859-1的每个字节正好对应同值的Unicode码位
+        Some(bytes.into_iter().map(char::from).collect())
+    }
+}
+
+/// Longest file name most file systems accept, in bytes
+const MAX_NAME_BYTES: usize = 255;
+
+/// Make a name taken from a URL or a server header safe to create in the
+/// current directory: drop any directory components, refuse `.` and `..`,
+/// replace characters the target OS does not allow in file names and cut
+/// names longer than `MAX_NAME_BYTES`
+pub fn sanitize_filename(name: &str) -> Option<String> {
+    // 名字可能带路径，只保留最后一段
+    let name = name.rsplit(['/', '\\']).next().unwrap_or("");
+
+    let mut cleaned: String = name
+        .chars()
+        .map(|c| if c.is_control() || is_reserved_char(c) { '_' } else { c })
+        .collect();
+
+    // Windows会悄悄去掉结尾的点和空格
+    if cfg!(windows) {
+        cleaned.truncate(cleaned.trim_end_matches(['.', ' ']).len());
+    }
+    let cleaned = cleaned.trim();
+
+    if cleaned.is_empty() || cleaned == "." || cleaned == ".." {
+        return None;
+    }
+    let cleaned = truncate_name(cleaned);
+    if cfg!(windows) && is_reserved_name(&cleaned) {
+        return Some(format!("_{}", cleaned));
+    }
+    Some(cleaned)
+}
+
+// 太长时从主名里截掉，扩展名尽量保留
+fn truncate_name(name: &str) -> String {
+    if name.len() <= MAX_NAME_BYTES {
+        return name.to_string();
+    }
+    let extension = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= 16 => &name[dot..],
+        _ => "",
+    };
+    let mut end = MAX_NAME_BYTES - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], extension)
+}
+
+fn is_reserved_char(c: char) -> bool {
+    cfg!(windows) && matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*')
+}
+
+// CON、NUL、COM1这类设备名在Windows上不能做文件名，带扩展名也不行
+fn is_reserved_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or("").to_ascii_uppercase();
+    matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL")
+        || ((stem.starts_with("COM") || stem.starts_with("LPT"))
+            && stem.len() == 4
+            && stem.as_bytes()[3].is_ascii_digit()
+            && stem.as_bytes()[3] != b'0')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_directory_components() {
+        assert_eq!(sanitize_filename("../../etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(sanitize_filename("/etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(sanitize_filename("..\\..\\windows\\win.ini").as_deref(), Some("win.ini"));
+        assert_eq!(sanitize_filename("C:\\boot.ini").as_deref(), Some("boot.ini"));
+        assert_eq!(sanitize_filename("dir/"), None);
+    }
+
+    #[test]
+    fn replaces_control_characters() {
+        assert_eq!(sanitize_filename("a\0b").as_deref(), Some("a_b"));
+        assert_eq!(sanitize_filename("line\nbreak\t.txt").as_deref(), Some("line_break_.txt"));
+        assert_eq!(sanitize_filename("bell\u{7}\u{1b}[0m").as_deref(), Some("bell__[0m"));
+    }
+
+    #[test]
+    fn refuses_empty_and_dot_names() {
+        for name in ["", " ", ".", "..", " .. ", "a/..", "a/.", "..\\.."] {
+            assert_eq!(sanitize_filename(name), None, "{:?}", name);
+        }
+        assert_eq!(sanitize_filename("...").as_deref(), if cfg!(windows) { None } else { Some("...") });
+        assert_eq!(sanitize_filename(".hidden").as_deref(), Some(".hidden"));
+    }
+
+    #[test]
+    fn recognizes_windows_device_names() {
+        for name in ["CON", "con.txt", "Nul", "aux.tar.gz", "COM1", "lpt9.log"] {
+            assert!(is_reserved_name(name), "{}", name);
+        }
+        for name in ["CONSOLE", "COM0", "COM10", "LPT", "nul_", "report.con"] {
+            assert!(!is_reserved_name(name), "{}", name);
+        }
+        let expected = if cfg!(windows) { "_con.txt" } else { "con.txt" };
+        assert_eq!(sanitize_filename("con.txt").as_deref(), Some(expected));
+    }
+
+    #[test]
+    fn truncates_long_names() {
+        let name = format!("{}.tar.gz", "a".repeat(300));
+        let cleaned = sanitize_filename(&name).unwrap();
+        assert_eq!(cleaned.len(), MAX_NAME_BYTES);
+        assert!(cleaned.ends_with("a.gz"), "{}", cleaned);
+
+        // 截断不能切开多字节字符
+        let cleaned = sanitize_filename(&"é".repeat(200)).unwrap();
+        assert_eq!(cleaned, "é".repeat(127));
+
+        let name = format!("x.{}", "y".repeat(300));
+        assert_eq!(sanitize_filename(&name).unwrap().len(), MAX_NAME_BYTES);
+    }
+
+    #[test]
+    fn reads_content_disposition() {
+        assert_eq!(from_content_disposition("attachment; filename=\"a b.zip\"").as_deref(), Some("a b.zip"));
+        assert_eq!(from_content_disposition("attachment; filename=plain.txt").as_deref(), Some("plain.txt"));
+        assert_eq!(
+            from_content_disposition("attachment; filename=\"x;y.txt\"; size=3").as_deref(),
+            Some("x;y.txt")
+        );
+        assert_eq!(from_content_disposition("inline").as_deref(), None);
+    }
+
+    #[test]
+    fn prefers_and_sanitizes_extended_filenames() {
+        let value = "attachment; filename=\"fallback.txt\"; filename*=UTF-8''%E2%82%AC%20rates.txt";
+        assert_eq!(from_content_disposition(value).as_deref(), Some("€ rates.txt"));
+        assert_eq!(
+            from_content_disposition("attachment; filename*=iso-8859-1'en'%A3%20rates.txt").as_deref(),
+            Some("£ rates.txt")
+        );
+        // 解码后的路径和控制字符一样要清理
+        assert_eq!(
+            from_content_disposition("attachment; filename*=UTF-8''..%2F..%2Fetc%2Fpasswd").as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(from_content_disposition("attachment; filename*=UTF-8''a%00b").as_deref(), Some("a_b"));
+        assert_eq!(from_content_disposition("attachment; filename*=UTF-8''..").as_deref(), None);
+        // 无效的UTF-8退回到普通的filename
+        assert_eq!(
+            from_content_disposition("attachment; filename=ok.txt; filename*=UTF-8''%FF").as_deref(),
+            Some("ok.txt")
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use crate::client::{self, IpFamily};
+use crate::output::{self, status, warning};
+use crate::errors::{Interrupted, check_filesize, file_error};
+use crate::options::Options;
+use crate::{DownloadMode, DownloadOptions, Transfer};
+use anyhow::anyhow;
+use percent_encoding::percent_decode_str;
+use suppaftp::tokio::AsyncFtpStream;
+use suppaftp::FtpError;
+use suppaftp::types::FileType;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::Duration;
+use url::Url;
+
+pub fn is_ftp_url(url: &str) -> bool {
+    Url::parse(url).map(|u| u.scheme() == "ftp").unwrap_or(false)
+}
+
+/// Where to connect and what to fetch, taken from an `ftp://` URL
+struct FtpTarget {
+    host: String,
+    port: u16,
+    path: String,
+    user: String,
+    password: String,
+}
+
+impl FtpTarget {
+    fn parse(url: &str, args: &Options) -> anyhow::Result<Self> {
+        let parsed = Url::parse(url)?;
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| anyhow!("No host in FTP URL {}", url))?
+            .to_string();
+        let path = percent_decode_str(parsed.path()).decode_utf8()?.into_owned();
+
+        // --user优先，其次是URL里的用户名，都没有时匿名登录
+        let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().into_owned();
+        let (user, password) = match args.user {
+            Some(ref user) => (user.clone(), args.password.clone().unwrap_or_default()),
+            None if !parsed.username().is_empty() => (
+                decode(parsed.username()),
+                parsed.password().map(decode).unwrap_or_default(),
+            ),
+            None => ("anonymous".to_string(), "anonymous@".to_string()),
+        };
+
+        Ok(FtpTarget {
+            host,
+            port: parsed.port().unwrap_or(21),
+            path,
+            user,
+            password,
+        })
+    }
+
+    async fn connect(&self, args: &Options) -> anyhow::Result<AsyncFtpStream> {
+        let addrs = client::lookup(&self.host, self.port, IpFamily::from_args(args)).await?;
+        let connect = tcp_connect(&addrs, args.bind_address);
+        let stream = match args.connect_timeout {
+            Some(secs) => tokio::time::timeout(Duration::from_secs(secs), connect)
+                .await
+                .map_err(|_| anyhow!("Connection to {} timed out", self.host))??,
+            None => connect.await?,
+        };
+        let mut ftp = AsyncFtpStream::connect_with_stream(stream).await?;
+        // 被动模式的数据连接也要从同一个地址发出
+        if let Some(local) = args.bind_address {
+            ftp = ftp.passive_stream_builder(move |addr| {
+                Box::pin(async move { tcp_connect(&[addr], Some(local)).await.map_err(FtpError::ConnectionError) })
+            });
+        }
+        ftp.login(self.user.as_str(), self.password.as_str())
+            .await
+            .map_err(|e| anyhow!("FTP login as {} failed: {}", self.user, e))?;
+        ftp.transfer_type(FileType::Binary).await?;
+        Ok(ftp)
+    }
+}
+
+/// Connect to the first of `addrs` that accepts, from `local` if given
+async fn tcp_connect(addrs: &[SocketAddr], local: Option<IpAddr>) -> std::io::Result<TcpStream> {
+    let mut last_error = None;
+    for &addr in addrs {
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        if let Some(local) = local {
+            socket.bind(SocketAddr::new(local, 0))?;
+        }
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no address to connect to")))
+}
+
+/// Download an `ftp://` URL over a passive-mode data connection, resuming with
+/// `REST` after a failed attempt or when `--continue` finds a partial file
+pub async fn download_ftp(
+    args: &Options,
+    url: &str,
+    savepath: &str,
+    opts: &DownloadOptions,
+) -> anyhow::Result<Transfer> {
+    let target = FtpTarget::parse(url, args)?;
+
+    let mut position = if opts.resume && !crate::paths::is_stdout(savepath) {
+        tokio::fs::metadata(savepath).await.map(|m| m.len()).unwrap_or(0)
+    } else {
+        0
+    };
+
+    let retry = opts.retry;
+    let mut attempt = 0;
+    let mut backoff = retry.backoff(0);
+    loop {
+        match download_attempt(args, &target, savepath, &mut position, opts).await {
+            Ok(mode) => {
+                return Ok(Transfer {
+                    mode,
+                    total_bytes: position,
+                    retries: attempt,
+                    digest: None,
+                })
+            }
+            // stdout上已经输出的内容无法撤回，不能重试
+            Err(e)
+                if attempt < retry.retries
+                    && !(crate::paths::is_stdout(savepath) && position > 0)
+                    && crate::errors::is_retryable(&e, &retry) =>
+            {
+                attempt += 1;
+                warning!("Retry {}: {}", attempt, e);
+                tokio::time::sleep(backoff.delay_for(attempt)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn download_attempt(
+    args: &Options,
+    target: &FtpTarget,
+    savepath: &str,
+    position: &mut u64,
+    opts: &DownloadOptions,
+) -> anyhow::Result<DownloadMode> {
+    let mut ftp = target.connect(args).await?;
+
+    // 不是所有服务器都支持SIZE，拿不到时就不显示进度条
+    let total_size = ftp.size(&target.path).await.ok().map(|n| n as u64);
+    if let Some(total_size) = total_size {
+        status!("File size: {} bytes", total_size);
+        if let Err(e) = check_filesize(opts.max_filesize, total_size, true) {
+            let _ = ftp.quit().await;
+            return Err(e);
+        }
+        if *position == total_size {
+            status!("File is already fully downloaded ({} bytes), skipping", total_size);
+            let _ = ftp.quit().await;
+            return Ok(DownloadMode::Skipped);
+        }
+        if *position > total_size {
+            warning!(
+                "Warning: local file ({} bytes) is larger than remote file ({} bytes), restarting download",
+                *position, total_size
+            );
+            *position = 0;
+        }
+    }
+
+    let offset = *position;
+    if offset > 0 {
+        match ftp.resume_transfer(offset as usize).await {
+            Ok(()) => status!("Resuming download at byte {}", offset),
+            Err(e) if crate::paths::is_stdout(savepath) => {
+                return Err(anyhow!("Server refused REST ({}), can't resume output to stdout", e));
+            }
+            Err(e) => {
+                warning!("Warning: server refused REST ({}), restarting download", e);
+                *position = 0;
+            }
+        }
+    }
+    let offset = *position;
+
+    let mut file: Box<dyn tokio::io::AsyncWrite + Unpin + Send> = if crate::paths::is_stdout(savepath) {
+        Box::new(tokio::io::stdout())
+    } else if offset > 0 {
+        let file = tokio::fs::OpenOptions::new().append(true).open(savepath).await;
+        Box::new(file.map_err(|e| file_error(e, savepath, "File open error"))?)
+    } else {
+        let file = tokio::fs::File::create(savepath).await;
+        Box::new(file.map_err(|e| file_error(e, savepath, "File create error"))?)
+    };
+
+    let mut stream = ftp
+        .retr_as_stream(&target.path)
+        .await
+        .map_err(|e| anyhow!("Failed to retrieve {}: {}", target.path, e))?;
+
+    let progress_bar = match total_size {
+        Some(size) => {
+            let pb = output::download_bar(size);
+            pb.set_position(offset);
+            Some(pb)
+        }
+        None => {
+            status!("Downloading... (size unknown)");
+            None
+        }
+    };
+
+    let mut buf = vec![0u8; 64 * 1024];
+    let result = async {
+        loop {
+            let n = tokio::select! {
+                n = stream.read(&mut buf) => n?,
+                _ = crate::errors::interrupted() => return Err(Interrupted.into()),
+            };
+            if n == 0 {
+                break;
+            }
+            check_filesize(opts.max_filesize, *position + n as u64, false)?;
+            file.write_all(&buf[..n])
+                .await
+                .map_err(|e| file_error(e, savepath, "File write error"))?;
+            *position += n as u64;
+
+            if let Some(ref pb) = progress_bar {
+                pb.inc(n as u64);
+            }
+            if let Some(ref limiter) = opts.rate_limiter {
+                limiter.consume(n as u64).await;
+            }
+            opts.charge_quota(n as u64)?;
+        }
+        Ok::<(), anyhow::Error>(())
+    }
+    .await;
+
+    // 出错时也要把已写入的数据刷到磁盘，重试时才能接着写
+    file.flush()
+        .await
+        .map_err(|e| file_error(e, savepath, "File write error"))?;
+    if let Err(e) = result {
+        if let Some(pb) = progress_bar {
+            pb.abandon();
+        }
+        return Err(e);
+    }
+
+    // 读完数据后还要等服务器的226回复，才能确认传输完整
+    stream
+        .finish()
+        .await
+        .map_err(|e| anyhow!("FTP transfer did not complete: {}", e))?;
+    let _ = ftp.quit().await;
+
+    if let Some(total_size) = total_size
+        && *position != total_size
+    {
+        return Err(anyhow!(
+            "File size mismatch: expected {} bytes, got {}",
+            total_size,
+            *position
+        ));
+    }
+
+    match progress_bar {
+        Some(pb) => output::finish_download_bar(&pb),
+        None => status!("Download completed! Downloaded {} bytes", *position - offset),
+    }
+    Ok(DownloadMode::Single)
+}
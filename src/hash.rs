@@ -0,0 +1,258 @@
+use anyhow::anyhow;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "md5" => Some(HashAlgorithm::Md5),
+            "sha1" => Some(HashAlgorithm::Sha1),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    /// Length of the algorithm's digest in hex digits
+    fn hex_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Md5 => 32,
+            HashAlgorithm::Sha1 => 40,
+            HashAlgorithm::Sha256 => 64,
+        }
+    }
+
+    fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            32 => Some(HashAlgorithm::Md5),
+            40 => Some(HashAlgorithm::Sha1),
+            64 => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+}
+
+/// Parse the algorithm name given to `--print-hash`
+pub fn parse_algorithm(name: &str) -> Result<HashAlgorithm, String> {
+    HashAlgorithm::from_name(name).ok_or_else(|| format!("unsupported hash algorithm `{}`, expected md5, sha1 or sha256", name))
+}
+
+/// The checksum passed via `--hash`, either `<hex>` or `<algo>:<hex>`
+#[derive(Clone, Debug)]
+pub struct ExpectedHash {
+    pub algorithm: HashAlgorithm,
+    pub hex: String,
+}
+
+impl ExpectedHash {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let value = value.trim();
+        let (algorithm, hex) = match value.split_once(':') {
+            Some((name, hex)) => {
+                let algorithm = HashAlgorithm::from_name(name)
+                    .ok_or_else(|| anyhow!("Unsupported hash algorithm: {}", name))?;
+                (algorithm, hex)
+            }
+            None => {
+                let algorithm = HashAlgorithm::from_hex_len(value.len()).ok_or_else(|| {
+                    anyhow!(
+                        "Cannot detect hash algorithm from a {}-character value (expected 32, 40 or 64)",
+                        value.len()
+                    )
+                })?;
+                (algorithm, value)
+            }
+        };
+
+        if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow!("Invalid hex digest: {}", hex));
+        }
+        // 截断或多贴了字符的摘要永远对不上，不如现在就报错
+        if hex.len() != algorithm.hex_len() {
+            return Err(anyhow!(
+                "A {} digest has {} hex characters, got {}: {}",
+                algorithm.name(),
+                algorithm.hex_len(),
+                hex.len(),
+                hex
+            ));
+        }
+
+        Ok(ExpectedHash {
+            algorithm,
+            hex: hex.to_ascii_lowercase(),
+        })
+    }
+
+    /// Look up `name` in a `sha256sum`-style file of `<hex>  <name>` lines
+    pub async fn from_checksum_file(path: &str, name: &str) -> anyhow::Result<Self> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| anyhow!("Failed to read checksum file {}: {}", path, e))?;
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((hex, entry)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            // 二进制模式的条目以*开头
+            let entry = entry.trim_start();
+            let entry = entry.strip_prefix('*').unwrap_or(entry);
+            let entry = entry.strip_prefix("./").unwrap_or(entry);
+            if entry == name || entry.rsplit('/').next() == Some(name) {
+                return Self::parse(hex);
+            }
+        }
+
+        Err(anyhow!("{} is not listed in checksum file {}", name, path))
+    }
+}
+
+/// A digest fed piece by piece, for hashing a download as it streams in
+pub enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+        }
+    }
+
+    pub fn finalize_hex(self) -> String {
+        let bytes = match self {
+            Hasher::Md5(h) => h.finalize().to_vec(),
+            Hasher::Sha1(h) => h.finalize().to_vec(),
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+        };
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Hash a file on disk without loading it into memory
+pub async fn compute_file_hash(path: &str, algorithm: HashAlgorithm) -> anyhow::Result<String> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| anyhow!("Failed to open {} for hashing: {}", path, e))?;
+    let mut hasher = Hasher::new(algorithm);
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hasher.finalize_hex())
+}
+
+pub async fn verify_file_hash(path: &str, expected: &ExpectedHash) -> anyhow::Result<()> {
+    let actual = compute_file_hash(path, expected.algorithm).await?;
+    check_digest(expected, &actual)
+}
+
+/// Compare `actual`, a hex digest made with `expected`'s algorithm, against `expected`
+pub fn check_digest(expected: &ExpectedHash, actual: &str) -> anyhow::Result<()> {
+    if actual != expected.hex {
+        return Err(anyhow!(
+            "hash mismatch: expected {}, got {}",
+            expected.hex,
+            actual
+        ));
+    }
+    Ok(())
+}
+
+/// Append a `sha256sum`-style entry for `file` to the `--manifest` at `path`,
+/// after a `# <url> <size>` comment line that `sha256sum -c` skips
+pub async fn append_manifest(path: &str, url: &str, file: &str, size: u64, sha256: &str) -> anyhow::Result<()> {
+    let entry = format!("# {} {}\n{}  {}\n", url, size, sha256, file);
+    let mut manifest = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .map_err(|e| anyhow!("Failed to open manifest {}: {}", path, e))?;
+    // 注释和校验值放在一次写入里，几个rget同时追加时不会交错
+    manifest
+        .write_all(entry.as_bytes())
+        .await
+        .map_err(|e| anyhow!("Failed to write to manifest {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_the_algorithm_from_the_length() {
+        for (hex, algorithm) in [
+            ("a".repeat(32), HashAlgorithm::Md5),
+            ("B".repeat(40), HashAlgorithm::Sha1),
+            ("0".repeat(64), HashAlgorithm::Sha256),
+        ] {
+            let expected = ExpectedHash::parse(&hex).unwrap();
+            assert_eq!(expected.algorithm, algorithm);
+            assert_eq!(expected.hex, hex.to_ascii_lowercase());
+        }
+        assert!(ExpectedHash::parse(&"a".repeat(33)).is_err());
+    }
+
+    #[test]
+    fn checks_the_length_for_the_named_algorithm() {
+        assert!(ExpectedHash::parse(&format!("md5:{}", "a".repeat(32))).is_ok());
+        assert!(ExpectedHash::parse(&format!("SHA1:{}", "a".repeat(40))).is_ok());
+        assert!(ExpectedHash::parse(&format!("sha256:{}", "a".repeat(64))).is_ok());
+        for value in [
+            format!("sha256:{}", "a".repeat(40)),
+            format!("sha256:{}", "a".repeat(63)),
+            format!("md5:{}", "a".repeat(64)),
+            format!("sha1:{}", "a".repeat(32)),
+        ] {
+            assert!(ExpectedHash::parse(&value).is_err(), "{}", value);
+        }
+        let error = ExpectedHash::parse("sha256:abcd").unwrap_err().to_string();
+        assert_eq!(error, "A sha256 digest has 64 hex characters, got 4: abcd");
+    }
+
+    #[test]
+    fn rejects_bad_digests() {
+        assert!(ExpectedHash::parse("md5:").is_err());
+        assert!(ExpectedHash::parse(&format!("md5:{}", "g".repeat(32))).is_err());
+        assert!(ExpectedHash::parse(&format!("crc32:{}", "a".repeat(8))).is_err());
+    }
+}
@@ -0,0 +1,902 @@
+//! Download files over HTTP(S), FTP and `file://` with parallel ranged
+//! requests, resuming and hash checks. This is the library behind the `rget`
+//! command; [`Downloader`] takes the same settings as its flags.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! let outcome = rget::Downloader::new("https://example.com/file.iso")
+//!     .savepath("file.iso")
+//!     .threads(8)
+//!     .download()
+//!     .await?;
+//! println!("{} bytes saved to {} in {:?}", outcome.total_bytes(), outcome.savepath(), outcome.elapsed());
+//! # Ok(())
+//! # }
+//! ```
+
+mod cli;
+mod client;
+mod config;
+mod cookies;
+mod downloader;
+mod errors;
+mod etag;
+mod filename;
+mod ftp;
+mod hash;
+mod local;
+mod metrics;
+mod multi;
+mod options;
+mod output;
+mod paths;
+mod probe;
+mod retry;
+mod single;
+mod state;
+mod strategy;
+mod throttle;
+mod units;
+
+#[doc(hidden)]
+pub use cli::run;
+pub use downloader::Downloader;
+
+use anyhow::{anyhow};
+use errors::{HttpError, Interrupted, NotModified, guard_download, is_fatal};
+use multi::download_multi_threaded;
+use options::Options;
+use output::{status, verbose, warning};
+use paths::{
+    check_dir_writable, is_directory_target, is_stdout, modified_time, prepare_savepath, refuse_existing,
+    remove_partial, resolve_savepath, set_modified_time, working_path,
+};
+use probe::{RemoteInfo, conditional_headers, probe_expected, spread_pool};
+use retry::RetryPolicy;
+use single::{ByteRange, download_byte_range, download_single_threaded, verify_resume_point};
+use state::ResumeState;
+use strategy::{MULTI_THREAD_MIN_SIZE, Strategy, decide_strategy};
+use throttle::{Quota, QuotaExceeded, RateLimiter};
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+use url::Url;
+
+/// Bounds for `--threads auto`. Downloads wait on the network rather than the
+/// CPU, so even one core gets two connections; past 16 more rarely help
+const MIN_AUTO_THREADS: u8 = 2;
+const MAX_AUTO_THREADS: u8 = 16;
+
+/// Thread count for `--threads auto`, from the available parallelism
+fn auto_threads() -> u8 {
+    let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    cpus.clamp(MIN_AUTO_THREADS as usize, MAX_AUTO_THREADS as usize) as u8
+}
+
+/// The URL with any `user:password@` credentials removed, for printing
+fn display_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed) => {
+            let _ = parsed.set_username("");
+            let _ = parsed.set_password(None);
+            parsed.to_string()
+        }
+        Err(_) => url.to_string(),
+    }
+}
+
+/// Per-download settings taken from the command line
+#[derive(Clone)]
+struct DownloadOptions {
+    threads: u8,
+    /// `--adaptive-threads`: scale the thread count with the file size
+    adaptive_threads: bool,
+    /// How many chunks download at once
+    max_concurrent: usize,
+    /// `--segment-size`: split into chunks of this size instead of one per thread
+    segment_size: Option<u64>,
+    /// `--max-range-size`: the largest range one request asks for
+    max_range_size: Option<u64>,
+    /// Files smaller than this are downloaded single-threaded
+    single_thread_below: u64,
+    /// `--range`: download only this part of the file, single-threaded
+    byte_range: Option<ByteRange>,
+    resume: bool,
+    /// `--verify-resume`: compare the bytes before each resume point with the server
+    verify_resume: bool,
+    /// `--content-on-error`: keep the body of an error response to save it
+    content_on_error: bool,
+    retry: RetryPolicy,
+    /// Shared by all threads, so the limit applies to the aggregate rate
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Shared by the whole batch
+    quota: Option<Arc<Quota>>,
+    /// `--max-filesize`, also enforced while streaming when the size isn't known up front
+    max_filesize: Option<u64>,
+    /// `--stats`: print per-thread statistics after a multi-threaded download
+    stats: bool,
+    /// Algorithm of `--hash`, or else `--print-hash`, to digest single-threaded
+    /// downloads with while they stream
+    hash_algorithm: Option<hash::HashAlgorithm>,
+    /// `--method`, GET unless given
+    method: reqwest::Method,
+    /// `--post-data` or the contents of `--post-file`
+    body: Option<Arc<Vec<u8>>>,
+}
+
+impl DownloadOptions {
+    /// Count `bytes` against `--quota`
+    fn charge_quota(&self, bytes: u64) -> anyhow::Result<()> {
+        if let Some(ref quota) = self.quota {
+            quota.consume(bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Anything but a plain GET can't be probed with HEAD or split into ranges
+    fn is_custom_request(&self) -> bool {
+        self.method != reqwest::Method::GET || self.body.is_some()
+    }
+}
+
+/// How a download was carried out
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DownloadMode {
+    /// The local file was already complete
+    Skipped,
+    Single,
+    Multi { threads: usize },
+}
+
+impl DownloadMode {
+    /// `skipped`, `single` or `multi`, as `--json` reports it
+    pub fn name(&self) -> &'static str {
+        match self {
+            DownloadMode::Skipped => "skipped",
+            DownloadMode::Single => "single",
+            DownloadMode::Multi { .. } => "multi",
+        }
+    }
+
+    /// Threads the file was downloaded with, 0 when it was already complete
+    pub fn threads(&self) -> usize {
+        match self {
+            DownloadMode::Skipped => 0,
+            DownloadMode::Single => 1,
+            DownloadMode::Multi { threads } => *threads,
+        }
+    }
+}
+
+/// Result of a finished download
+#[derive(Clone, Debug)]
+struct Transfer {
+    mode: DownloadMode,
+    /// Size of the output once the download finished
+    total_bytes: u64,
+    /// Failed attempts that were retried, summed over all threads
+    retries: u32,
+    /// Digest of the output computed while it streamed in, for a single-threaded
+    /// download from the first byte; the others are hashed afterwards
+    digest: Option<hash::ExpectedHash>,
+}
+
+/// Which code path fetches a URL, picked by its scheme
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Backend {
+    Http,
+    Ftp,
+    File,
+}
+
+impl Backend {
+    fn for_url(url: &str) -> Self {
+        if ftp::is_ftp_url(url) {
+            Backend::Ftp
+        } else if local::is_file_url(url) {
+            Backend::File
+        } else {
+            Backend::Http
+        }
+    }
+
+    /// Fetch `url` into `savepath` without a HEAD probe, as done for stdout
+    /// and for the schemes that have no probe
+    async fn fetch_unprobed(
+        self,
+        args: &Options,
+        client: &reqwest::Client,
+        url: &str,
+        savepath: &str,
+        opts: &DownloadOptions,
+    ) -> anyhow::Result<Transfer> {
+        match self {
+            Backend::Http => download_single_threaded(client, url, savepath, 0, opts).await,
+            Backend::Ftp => ftp::download_ftp(args, url, savepath, opts).await,
+            Backend::File => local::copy_file_url(url, savepath, opts).await,
+        }
+    }
+}
+
+/// Download the file `info` describes; `mirrors` are extra URLs already
+/// checked to serve the same bytes, which share the chunks with `info.url`
+async fn download_file(
+    client: &reqwest::Client,
+    info: &RemoteInfo,
+    mirrors: &[String],
+    savepath: &str,
+    opts: &DownloadOptions,
+) -> anyhow::Result<Transfer> {
+    let url = info.url.as_str();
+    let urls: Vec<String> = std::iter::once(info.url.clone()).chain(mirrors.iter().cloned()).collect();
+    let total_size_opt = info.total_size;
+    let supports_ranges = info.supports_ranges;
+
+    if let Some(range) = opts.byte_range {
+        return download_byte_range(client, url, savepath, info, range, opts).await;
+    }
+
+    // 多线程断点续传：优先使用.rget状态文件
+    let state_path = state::sidecar_path(savepath);
+    let has_sidecar = tokio::fs::try_exists(&state_path).await.unwrap_or(false);
+    if opts.resume && supports_ranges && has_sidecar {
+        match ResumeState::load(&state_path).await {
+            Ok(saved) if Some(saved.total_size) == total_size_opt => {
+                status!(
+                    "Resuming {} chunks from {} ({} of {} bytes done)",
+                    saved.chunks.len(),
+                    state_path,
+                    saved.downloaded(),
+                    saved.total_size
+                );
+                if opts.verify_resume {
+                    for chunk in saved.chunks.iter().filter(|c| c.downloaded > 0 && !c.is_complete()) {
+                        let position = chunk.start + chunk.downloaded;
+                        verify_resume_point(client, url, savepath, position, chunk.downloaded).await?;
+                    }
+                    status!("Verified the data before each chunk's resume point");
+                }
+                return download_multi_threaded(client, &urls, savepath, saved, opts).await;
+            }
+            Ok(_) => warning!("Warning: {} doesn't match the remote file size, restarting download", state_path),
+            Err(e) => warning!("Warning: failed to read {}: {}, restarting download", state_path, e),
+        }
+    }
+
+    // 断点续传：检查本地已有的字节数。有状态文件说明是多线程下载留下的，
+    // 文件已经预分配到完整大小，不能按长度接着下载
+    let existing_size = if opts.resume && !has_sidecar {
+        tokio::fs::metadata(savepath).await.map(|m| m.len()).unwrap_or(0)
+    } else {
+        0
+    };
+
+    if existing_size > 0 {
+        match total_size_opt {
+            Some(total_size) if existing_size == total_size => {
+                status!("File is already fully downloaded ({} bytes), skipping", total_size);
+                return Ok(Transfer {
+                    mode: DownloadMode::Skipped,
+                    total_bytes: total_size,
+                    retries: 0,
+                    digest: None,
+                });
+            }
+            Some(total_size) if existing_size > total_size => {
+                warning!(
+                    "Warning: local file ({} bytes) is larger than remote file ({} bytes), restarting download",
+                    existing_size, total_size
+                );
+            }
+            Some(_) if !supports_ranges => {
+                warning!("Warning: server doesn't support range requests, restarting download");
+            }
+            Some(total_size) => {
+                if opts.verify_resume {
+                    verify_resume_point(client, url, savepath, existing_size, existing_size).await?;
+                    status!("Verified the data before byte {}", existing_size);
+                }
+                status!("Resuming download at byte {} of {}", existing_size, total_size);
+                return download_single_threaded(client, url, savepath, existing_size, opts).await;
+            }
+            None => {
+                warning!("Warning: server doesn't provide content-length, cannot resume, restarting download");
+            }
+        }
+    }
+
+    let strategy = decide_strategy(
+        total_size_opt,
+        supports_ranges,
+        opts.threads,
+        opts.adaptive_threads,
+        opts.segment_size,
+        opts.single_thread_below,
+    );
+    let (total_size, ranges) = match strategy {
+        Strategy::UnknownSize => {
+            status!("Server doesn't provide content-length, using single-threaded download");
+            return download_single_threaded(client, url, savepath, 0, opts).await;
+        }
+        Strategy::Empty => {
+            status!("Remote file is empty, creating an empty file");
+            tokio::fs::File::create(savepath)
+                .await
+                .map_err(|e| anyhow!("Failed to create {}: {}", savepath, e))?;
+            return Ok(Transfer {
+                mode: DownloadMode::Single,
+                total_bytes: 0,
+                retries: 0,
+                digest: None,
+            });
+        }
+        Strategy::Single { total_size } => {
+            status!("File size: {} bytes", total_size);
+            status!("Server supports range requests: {}", supports_ranges);
+            if supports_ranges && opts.threads > 1 && total_size < opts.single_thread_below {
+                verbose!("File is smaller than {} bytes, not worth splitting", opts.single_thread_below);
+            }
+            status!("Using single-threaded download");
+            return download_single_threaded(client, url, savepath, 0, opts).await;
+        }
+        Strategy::Multi { total_size, ranges } => {
+            status!("File size: {} bytes", total_size);
+            status!("Server supports range requests: {}", supports_ranges);
+            (total_size, ranges)
+        }
+    };
+    match opts.segment_size {
+        Some(segment_size) => status!(
+            "Using {}-threaded download with {} segments of {} bytes",
+            opts.max_concurrent.clamp(1, ranges.len()),
+            ranges.len(),
+            segment_size
+        ),
+        None => status!(
+            "Using {}-threaded download with chunk size: {} bytes",
+            ranges.len(),
+            total_size.div_ceil(ranges.len() as u64)
+        ),
+    }
+
+    if urls.len() > 1 {
+        status!("Spreading chunks across {} mirrors", urls.len());
+    }
+    download_multi_threaded(client, &urls, savepath, ResumeState::new(total_size, &ranges), opts).await
+}
+
+/// What a completed download did, returned by [`Downloader::download`] and
+/// reported by `--json`
+#[derive(Clone, Debug)]
+pub struct DownloadOutcome {
+    final_url: String,
+    savepath: String,
+    transfer: Transfer,
+    elapsed: Duration,
+    hash: HashStatus,
+    /// Computed for `--print-hash`
+    digest: Option<hash::ExpectedHash>,
+}
+
+/// Whether a download was checked against an expected hash. A mismatch is
+/// an error rather than an outcome
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashStatus {
+    /// No hash was given, neither directly nor through a checksum file
+    Unchecked,
+    Verified,
+}
+
+impl DownloadOutcome {
+    /// The URL the file came from, after redirects and mirror selection
+    pub fn final_url(&self) -> &str {
+        &self.final_url
+    }
+
+    /// Where the file was saved
+    pub fn savepath(&self) -> &str {
+        &self.savepath
+    }
+
+    /// Size of the saved file in bytes, including any part kept from an
+    /// earlier run
+    pub fn total_bytes(&self) -> u64 {
+        self.transfer.total_bytes
+    }
+
+    /// Time the download took, including probing and hash checks
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn mode(&self) -> DownloadMode {
+        self.transfer.mode
+    }
+
+    /// Failed attempts that were retried, summed over all threads
+    pub fn retries(&self) -> u32 {
+        self.transfer.retries
+    }
+
+    pub fn hash_status(&self) -> HashStatus {
+        self.hash
+    }
+
+    /// The algorithm name and hex digest printed for `--print-hash`
+    pub fn digest(&self) -> Option<(&'static str, &str)> {
+        self.digest.as_ref().map(|d| (d.algorithm.name(), d.hex.as_str()))
+    }
+}
+
+/// Thread count for a download; compression forces a single thread
+fn download_threads(args: &Options) -> u8 {
+    // 解压后的长度和Content-Length对不上，分块的偏移也就没有意义
+    if args.compression { 1 } else { args.threads }
+}
+
+/// Download one URL and verify it, as requested on the command line
+async fn run_download(
+    args: &Options,
+    client: &reqwest::Client,
+    url: &str,
+    savepath: Option<&str>,
+    expected_hash: Option<&hash::ExpectedHash>,
+    quota: Option<&Arc<Quota>>,
+) -> anyhow::Result<DownloadOutcome> {
+    let started = Instant::now();
+    let threads = download_threads(args);
+    let body = match (&args.post_data, &args.post_file) {
+        (Some(data), _) => Some(Arc::new(data.clone().into_bytes())),
+        (None, Some(file)) => Some(Arc::new(
+            tokio::fs::read(file)
+                .await
+                .map_err(|e| anyhow!("Failed to read --post-file {}: {}", file, e))?,
+        )),
+        (None, None) => None,
+    };
+    let opts = DownloadOptions {
+        threads,
+        adaptive_threads: args.adaptive_threads,
+        max_concurrent: args.max_concurrent.unwrap_or(threads as usize),
+        segment_size: args.segment_size,
+        max_range_size: args.max_range_size,
+        single_thread_below: args.prefer_single_thread_below.unwrap_or(MULTI_THREAD_MIN_SIZE),
+        byte_range: args.range,
+        resume: args.resume,
+        verify_resume: args.verify_resume,
+        content_on_error: args.content_on_error,
+        retry: RetryPolicy {
+            retries: args.retries,
+            delay: Duration::from_millis(args.retry_delay),
+            max_delay: Duration::from_millis(args.retry_max_delay),
+            seed: args.deterministic.then_some(0),
+            http_statuses: if args.retry_on_http.is_empty() {
+                retry::StatusSet::new(&retry::DEFAULT_RETRY_STATUSES)
+            } else {
+                retry::StatusSet::new(&args.retry_on_http)
+            },
+        },
+        rate_limiter: args.limit_rate.map(|rate| Arc::new(RateLimiter::new(rate))),
+        quota: quota.cloned(),
+        max_filesize: args.max_filesize,
+        stats: args.stats,
+        hash_algorithm: expected_hash
+            .map(|e| e.algorithm)
+            .or(args.print_hash)
+            .or(args.manifest.as_ref().map(|_| hash::HashAlgorithm::Sha256)),
+        method: args.method.clone().unwrap_or(if body.is_some() { reqwest::Method::POST } else { reqwest::Method::GET }),
+        body,
+    };
+
+    status!("Downloading from URL: {}", display_url(url));
+    let backend = Backend::for_url(url);
+    if opts.is_custom_request() {
+        if backend != Backend::Http {
+            return Err(anyhow!("--method, --post-data and --post-file only work with HTTP(S) URLs"));
+        }
+        status!("Sending a {} request, using single-threaded download", opts.method);
+    } else if args.no_range_probe && backend == Backend::Http {
+        status!("Not probing the server, using single-threaded download");
+    }
+    if args.range.is_some() && backend != Backend::Http {
+        return Err(anyhow!("--range only works with HTTP(S) URLs"));
+    }
+    if args.unix_socket.is_some() && backend != Backend::Http {
+        return Err(anyhow!("--unix-socket only works with HTTP(S) URLs"));
+    }
+
+    match (&args.expect_type, backend) {
+        (Some(_), Backend::Ftp) => warning!("Warning: FTP servers don't report a Content-Type, ignoring --expect-type"),
+        (Some(_), Backend::File) => warning!("Warning: local files have no Content-Type, ignoring --expect-type"),
+        (Some(_), Backend::Http) if savepath.is_some_and(is_stdout) => {
+            // 写到stdout时默认不发HEAD，这里为了检查类型单独探测一次
+            guard_download(args, started, probe_expected(args, client, url, Default::default()))
+                .await
+                .map_err(|e| anyhow!("Download failed: {}", e))?;
+        }
+        _ => {}
+    }
+
+    // stdout不支持seek，只能单线程下载
+    if let Some(path) = savepath.filter(|p| is_stdout(p)) {
+        status!("Saving to path: {}", path);
+        let transfer = guard_download(args, started, backend.fetch_unprobed(args, client, url, path, &opts))
+            .await
+            .map_err(|e| anyhow!("Download failed: {}", e))?;
+        status!("Download completed successfully.");
+        return Ok(DownloadOutcome {
+            final_url: url.to_string(),
+            savepath: path.to_string(),
+            transfer,
+            elapsed: started.elapsed(),
+            hash: HashStatus::Unchecked,
+            digest: None,
+        });
+    }
+
+    // 指定了文件名时在发起请求之前检查，尽早失败；否则要等HEAD返回后才能确定文件名
+    let explicit_path = match savepath {
+        Some(p) if !is_directory_target(p).await => Some(prepare_savepath(args, p, true).await?),
+        _ => None,
+    };
+    // 缓存过这个文件的ETag时先带上If-None-Match问服务器，没变就不算覆盖，等探测之后再拒绝已有的文件
+    let mut check_existing = false;
+    if let Some(ref p) = explicit_path {
+        if !args.no_cache && !args.force && etag::is_cached(&display_url(url), p).await {
+            check_existing = true;
+        } else {
+            refuse_existing(args, p).await?;
+        }
+    }
+
+    // 下载先写到.part文件里，校验通过后再改名
+    let mut work = match explicit_path {
+        Some(ref p) => Some(working_path(args, p).await?),
+        None => None,
+    };
+    // 记下已有文件的修改时间，失败时没被改动过的文件不删
+    let mut preexisting = match work {
+        Some(ref w) => modified_time(w).await,
+        None => None,
+    };
+    let mut path = explicit_path;
+    match (path.as_deref(), savepath) {
+        (Some(p), _) => status!("Saving to path: {}", p),
+        (None, Some(dir)) => {
+            if !tokio::fs::try_exists(dir).await.unwrap_or(false) {
+                tokio::fs::create_dir_all(dir)
+                    .await
+                    .map_err(|e| anyhow!("Failed to create directory {}: {}", dir, e))?;
+            }
+            // 文件名要等服务器回复才知道，先确认目录能写
+            check_dir_writable(std::path::Path::new(dir), dir).await?;
+        }
+        _ => {}
+    }
+
+    // 依次尝试主地址和各个镜像，换镜像之后接着已经下载的部分继续
+    let candidates: Vec<&str> = std::iter::once(url)
+        .chain(args.mirrors.iter().map(String::as_str))
+        .collect();
+    let mut opts = opts;
+    let mut failure = None;
+    let mut outcome = None;
+    let mut up_to_date = false;
+    let mut last_modified = None;
+    // 成功后记下这次的ETag，下次带上If-None-Match
+    let mut seen_etag = None;
+    for (n, &candidate) in candidates.iter().enumerate() {
+        let has_next = n + 1 < candidates.len();
+        if n > 0 {
+            warning!("Trying mirror {}", display_url(candidate));
+        }
+        let backend = Backend::for_url(candidate);
+
+        // FTP和本地文件没有HEAD，直接在下载时获取大小；POST之类的请求不能用HEAD探测
+        let info = if backend != Backend::Http || opts.is_custom_request() || args.no_range_probe {
+            None
+        } else {
+            // 304说明本地的文件已经是最新的；--force时只有明确给了--if-modified-since才问
+            let local = if args.if_modified_since.is_none() && (args.no_cache || args.force) {
+                None
+            } else {
+                match path {
+                    Some(ref p) => Some(p.clone()),
+                    None => resolve_savepath(savepath, candidate, None).await.ok(),
+                }
+            };
+            let conditions = match local {
+                Some(ref local) => conditional_headers(args, candidate, local).await,
+                None => Default::default(),
+            };
+            match guard_download(args, started, probe_expected(args, client, candidate, conditions)).await {
+                Ok(info) => Some(info),
+                Err(e) if e.is::<NotModified>() && let Some(local) = local => {
+                    status!("{} is not modified on the server, up to date", local);
+                    path.get_or_insert(local);
+                    up_to_date = true;
+                    break;
+                }
+                // 探测失败时照样发GET，才能拿到要保存的错误页面
+                Err(e) if args.content_on_error && e.is::<HttpError>() => {
+                    verbose!("{}, downloading anyway for --content-on-error", e);
+                    None
+                }
+                Err(e) => {
+                    let fatal = is_fatal(&e);
+                    if has_next && !fatal {
+                        warning!("{}: {}", display_url(candidate), e);
+                    }
+                    failure = Some(e);
+                    if fatal {
+                        break;
+                    }
+                    continue;
+                }
+            }
+        };
+
+        if std::mem::take(&mut check_existing)
+            && let Some(ref p) = path
+        {
+            refuse_existing(args, p).await?;
+        }
+        let target = match work {
+            Some(ref w) => w.clone(),
+            None => {
+                let suggested = info.as_ref().and_then(|i| i.filename.as_deref());
+                let resolved = resolve_savepath(savepath, candidate, suggested).await?;
+                let prepared = prepare_savepath(args, &resolved, false).await?;
+                status!("Saving to path: {}", prepared);
+                let w = working_path(args, &prepared).await?;
+                preexisting = modified_time(&w).await;
+                path = Some(prepared);
+                work = Some(w.clone());
+                w
+            }
+        };
+
+        if args.timestamping {
+            match info.as_ref().map(|i| i.last_modified) {
+                Some(Some(remote)) => {
+                    let final_path = path.as_deref().unwrap_or(&target);
+                    if modified_time(final_path).await.is_some_and(|local| local >= remote) {
+                        status!("{} is up to date with the server, not downloading", final_path);
+                        up_to_date = true;
+                        break;
+                    }
+                    last_modified = Some(remote);
+                }
+                Some(None) => warning!("Warning: server didn't send Last-Modified, downloading anyway"),
+                None => warning!("Warning: --timestamping only works for HTTP(S) URLs, downloading anyway"),
+            }
+        }
+        if args.if_modified_since.is_some() && info.is_none() {
+            warning!("Warning: --if-modified-since only works for HTTP(S) URLs, downloading anyway");
+        }
+
+        // 分块模式下先确认其余镜像上的文件和这个一样大
+        let spread = match info {
+            Some(ref info) if args.spread_mirrors => {
+                guard_download(args, started, spread_pool(client, info, &candidates[n + 1..])).await?
+            }
+            _ => Vec::new(),
+        };
+
+        let result = match info {
+            Some(ref info) => {
+                status!("Using {} threads", opts.threads);
+                guard_download(args, started, download_file(client, info, &spread, &target, &opts)).await
+            }
+            None => {
+                guard_download(args, started, backend.fetch_unprobed(args, client, candidate, &target, &opts)).await
+            }
+        };
+        match result {
+            Ok(transfer) => {
+                if let Some(ref info) = info {
+                    seen_etag = Some((display_url(candidate), info.etag.clone()));
+                }
+                let final_url = info.map(|i| i.final_url).unwrap_or_else(|| candidate.to_string());
+                outcome = Some((final_url, transfer));
+                break;
+            }
+            Err(e) => {
+                // 超时、本地磁盘错误、文件过大和Ctrl-C换镜像也解决不了
+                let fatal = is_fatal(&e);
+                if has_next && !fatal {
+                    warning!("{}: {}", display_url(candidate), e);
+                }
+                failure = Some(e);
+                if fatal {
+                    break;
+                }
+                opts.resume = true;
+            }
+        }
+    }
+
+    if up_to_date && let Some(path) = path {
+        let total_bytes = tokio::fs::metadata(&path).await.map(|m| m.len()).unwrap_or(0);
+        return Ok(DownloadOutcome {
+            final_url: url.to_string(),
+            savepath: path,
+            transfer: Transfer {
+                mode: DownloadMode::Skipped,
+                total_bytes,
+                retries: 0,
+                digest: None,
+            },
+            elapsed: started.elapsed(),
+            hash: HashStatus::Unchecked,
+            digest: None,
+        });
+    }
+
+    let (final_url, transfer) = match outcome {
+        Some(done) => {
+            status!("Download completed successfully.");
+            done
+        }
+        None => {
+            let e = failure.unwrap_or_else(|| anyhow!("no URL to download"));
+            // 中断的下载保留进度，之后可以续传
+            if e.is::<Interrupted>() && work.is_some() {
+                return Err(anyhow!("Download {}, resume with --continue", e));
+            }
+            if e.is::<QuotaExceeded>() && work.is_some() {
+                return Err(anyhow!("Download stopped: {}, resume with --continue", e));
+            }
+            if let Some(ref work) = work {
+                remove_partial(args, work, preexisting).await;
+            }
+            if args.content_on_error
+                && let Some(content) = e.downcast_ref::<HttpError>().and_then(|h| h.content.as_ref())
+                && let Some(ref path) = path
+            {
+                match tokio::fs::write(path, content).await {
+                    Ok(()) => status!("Saved the server's error response ({} bytes) to {}", content.len(), path),
+                    Err(e) => warning!("Warning: failed to save the error response to {}: {}", path, e),
+                }
+            }
+            return Err(anyhow!("Download failed: {}", e));
+        }
+    };
+    // 下载成功时两者一定已经确定
+    let (Some(path), Some(work)) = (path, work) else {
+        return Err(anyhow!("no output path"));
+    };
+    let (path, work) = (path.as_str(), work.as_str());
+
+    // 校验文件里按下载后的文件名查找
+    let from_checksum_file = match args.checksum_file {
+        Some(ref sums) => {
+            let name = std::path::Path::new(path)
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.to_string());
+            Some(hash::ExpectedHash::from_checksum_file(sums, &name).await?)
+        }
+        None => None,
+    };
+    let expected_hash = from_checksum_file.as_ref().or(expected_hash);
+
+    // 流式算出的hash算法相同时直接比较，多线程下载乱序写入，只能之后再读一遍文件
+    let streamed = transfer.digest.as_ref();
+    if let Some(expected) = expected_hash {
+        let result = match streamed.filter(|d| d.algorithm == expected.algorithm) {
+            Some(digest) => {
+                verbose!("Using the {} hash computed during the download", digest.algorithm.name());
+                hash::check_digest(expected, &digest.hex)
+            }
+            None => {
+                status!("Verifying {} hash...", expected.algorithm.name());
+                hash::verify_file_hash(work, expected).await
+            }
+        };
+        if let Err(e) = result {
+            // 内容不对，接着下载也没有意义
+            if work != path && !args.keep_partial {
+                let _ = tokio::fs::remove_file(work).await;
+            }
+            return Err(e);
+        }
+        status!("Hash verified: {}", expected.hex);
+    }
+
+    // 下载并校验成功后删除状态文件
+    let state_path = state::sidecar_path(work);
+    if tokio::fs::try_exists(&state_path).await.unwrap_or(false) {
+        tokio::fs::remove_file(&state_path).await?;
+    }
+    if work != path {
+        tokio::fs::rename(work, path)
+            .await
+            .map_err(|e| anyhow!("Failed to rename {} to {}: {}", work, path, e))?;
+    }
+    if let Some(time) = last_modified {
+        set_modified_time(path, time).await?;
+    }
+    if !args.no_cache
+        && let Some((key, etag)) = seen_etag
+        && let Err(e) = etag::remember(&key, path, etag.as_deref()).await
+    {
+        warning!("Warning: failed to update the ETag cache: {}", e);
+    }
+
+    let digest = match args.print_hash {
+        // 已经校验过同一种算法时直接用校验值，不必再读一遍文件
+        Some(algorithm) => match expected_hash.or(streamed).filter(|e| e.algorithm == algorithm) {
+            Some(expected) => Some(expected.clone()),
+            None => Some(hash::ExpectedHash {
+                algorithm,
+                hex: hash::compute_file_hash(path, algorithm).await?,
+            }),
+        },
+        None => None,
+    };
+    if let Some(ref digest) = digest {
+        output::log_line(
+            output::LogLevel::Info,
+            format_args!("{} {}: {}", digest.algorithm.name(), path, digest.hex),
+        );
+    }
+
+    if let Some(ref manifest) = args.manifest {
+        let known = [expected_hash, streamed, digest.as_ref()]
+            .into_iter()
+            .flatten()
+            .find(|d| d.algorithm == hash::HashAlgorithm::Sha256);
+        let sha256 = match known {
+            Some(known) => known.hex.clone(),
+            None => hash::compute_file_hash(path, hash::HashAlgorithm::Sha256).await?,
+        };
+        hash::append_manifest(manifest, &display_url(url), path, transfer.total_bytes, &sha256).await?;
+    }
+
+    Ok(DownloadOutcome {
+        final_url,
+        savepath: path.to_string(),
+        transfer,
+        elapsed: started.elapsed(),
+        hash: if expected_hash.is_some() { HashStatus::Verified } else { HashStatus::Unchecked },
+        digest,
+    })
+}
+
+/// Check a URL from the command line or a list and put it in canonical form:
+/// `https://` is assumed when there is no scheme, and characters that aren't
+/// allowed in URLs, like spaces, are percent-encoded
+fn normalize_url(raw: &str) -> anyhow::Result<String> {
+    let raw = raw.trim();
+    // 不能只靠Url::parse判断有没有scheme，example.com:8080/x会被当成scheme是example.com
+    let with_scheme = if raw.contains("://") {
+        raw.to_string()
+    } else {
+        format!("https://{}", raw)
+    };
+    let url = Url::parse(&with_scheme).map_err(|e| anyhow!("Invalid URL {}: {}", raw, e))?;
+    match url.scheme() {
+        "http" | "https" | "ftp" | "file" => {}
+        scheme => return Err(anyhow!("Unsupported URL scheme '{}' in {}", scheme, raw)),
+    }
+    if url.scheme() != "file" && url.host_str().is_none_or(str::is_empty) {
+        return Err(anyhow!("Invalid URL {}: no host", raw));
+    }
+    if with_scheme != raw {
+        verbose!("No scheme in {}, using {}", raw, url);
+    }
+    Ok(url.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_forces_one_thread() {
+        let args = Options { threads: 8, ..Options::default() };
+        assert_eq!(download_threads(&args), 8);
+        let args = Options { compression: true, ..args };
+        assert_eq!(download_threads(&args), 1);
+    }
+}
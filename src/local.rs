@@ -0,0 +1,111 @@
+use crate::output::{self, status, warning};
+use crate::errors::{Interrupted, check_filesize, file_error};
+use crate::{DownloadMode, DownloadOptions, Transfer};
+use anyhow::anyhow;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use url::Url;
+
+pub fn is_file_url(url: &str) -> bool {
+    Url::parse(url).map(|u| u.scheme() == "file").unwrap_or(false)
+}
+
+/// Copy the file a `file://` URL points at, with the same progress bar and
+/// `--continue` handling as a single-threaded download
+pub async fn copy_file_url(url: &str, savepath: &str, opts: &DownloadOptions) -> anyhow::Result<Transfer> {
+    let source = Url::parse(url)?
+        .to_file_path()
+        .map_err(|_| anyhow!("Not a local file path: {}", url))?;
+    let mut input = tokio::fs::File::open(&source)
+        .await
+        .map_err(|e| anyhow!("Failed to open {}: {}", source.display(), e))?;
+    // 源文件和目标是同一个文件时，创建目标会把源文件清空
+    if let (Ok(a), Ok(b)) = (tokio::fs::canonicalize(&source).await, tokio::fs::canonicalize(savepath).await)
+        && a == b
+    {
+        return Err(anyhow!("{} and {} are the same file", source.display(), savepath));
+    }
+    let total_size = input.metadata().await?.len();
+    status!("File size: {} bytes", total_size);
+    check_filesize(opts.max_filesize, total_size, true)?;
+
+    // 断点续传：检查本地已有的字节数
+    let mut offset = if opts.resume && !crate::paths::is_stdout(savepath) {
+        tokio::fs::metadata(savepath).await.map(|m| m.len()).unwrap_or(0)
+    } else {
+        0
+    };
+    if offset == total_size && offset > 0 {
+        status!("File is already fully downloaded ({} bytes), skipping", total_size);
+        return Ok(Transfer {
+            mode: DownloadMode::Skipped,
+            total_bytes: total_size,
+            retries: 0,
+            digest: None,
+        });
+    }
+    if offset > total_size {
+        warning!(
+            "Warning: local file ({} bytes) is larger than the source ({} bytes), restarting copy",
+            offset, total_size
+        );
+        offset = 0;
+    }
+    if offset > 0 {
+        status!("Resuming copy at byte {} of {}", offset, total_size);
+        input.seek(std::io::SeekFrom::Start(offset)).await?;
+    }
+
+    let mut output: Box<dyn tokio::io::AsyncWrite + Unpin + Send> = if crate::paths::is_stdout(savepath) {
+        Box::new(tokio::io::stdout())
+    } else if offset > 0 {
+        let file = tokio::fs::OpenOptions::new().append(true).open(savepath).await;
+        Box::new(file.map_err(|e| file_error(e, savepath, "File open error"))?)
+    } else {
+        let file = tokio::fs::File::create(savepath).await;
+        Box::new(file.map_err(|e| file_error(e, savepath, "File create error"))?)
+    };
+
+    let progress_bar = output::download_bar(total_size);
+    progress_bar.set_position(offset);
+
+    let mut position = offset;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = tokio::select! {
+            n = input.read(&mut buf) => n.map_err(|e| anyhow!("Failed to read {}: {}", source.display(), e))?,
+            _ = crate::errors::interrupted() => {
+                // 留下已经复制的部分，之后可以续传
+                let _ = output.flush().await;
+                return Err(Interrupted.into());
+            }
+        };
+        if n == 0 {
+            break;
+        }
+        output
+            .write_all(&buf[..n])
+            .await
+            .map_err(|e| file_error(e, savepath, "File write error"))?;
+        position += n as u64;
+        progress_bar.inc(n as u64);
+        if let Some(ref limiter) = opts.rate_limiter {
+            limiter.consume(n as u64).await;
+        }
+        if let Err(e) = opts.charge_quota(n as u64) {
+            let _ = output.flush().await;
+            return Err(e);
+        }
+    }
+    output
+        .flush()
+        .await
+        .map_err(|e| file_error(e, savepath, "File write error"))?;
+
+    output::finish_download_bar(&progress_bar);
+    Ok(Transfer {
+        mode: DownloadMode::Single,
+        total_bytes: position,
+        retries: 0,
+        digest: None,
+    })
+}
@@ -0,0 +1,132 @@
+use anyhow::anyhow;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// What `--metrics-file` reports for one finished download
+pub struct Sample {
+    pub host: String,
+    pub filename: String,
+    /// Where the file was saved; tells apart files of the same name from the same host
+    pub savepath: String,
+    pub bytes: u64,
+    pub duration: Duration,
+    pub retries: u32,
+    pub threads: usize,
+}
+
+/// A metric's name, type, help text and value, in the order they're written
+type Metric = (&'static str, &'static str, &'static str, fn(&Sample) -> String);
+
+const METRICS: [Metric; 4] = [
+    ("rget_bytes_downloaded_total", "counter", "Size of the downloaded file in bytes", |s| s.bytes.to_string()),
+    (
+        "rget_duration_seconds",
+        "gauge",
+        "Time the download took, including probing and hash checks",
+        |s| format!("{:.3}", s.duration.as_secs_f64()),
+    ),
+    (
+        "rget_retries_total",
+        "counter",
+        "Failed attempts that were retried, summed over all threads",
+        |s| s.retries.to_string(),
+    ),
+    (
+        "rget_threads",
+        "gauge",
+        "Threads the file was downloaded with, 0 when it was already complete",
+        |s| s.threads.to_string(),
+    ),
+];
+
+/// A label value with `\`, `"` and newlines escaped as the text format requires
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// `samples` in the Prometheus text format, one series per saved file. A file
+/// downloaded twice only keeps its last sample, since the collector rejects
+/// a file with the same labels twice
+fn render(samples: &[Sample]) -> String {
+    let labels = |s: &Sample| (s.host.clone(), s.filename.clone(), s.savepath.clone());
+    let latest: Vec<&Sample> = samples
+        .iter()
+        .enumerate()
+        .filter(|&(i, sample)| !samples[i + 1..].iter().any(|later| labels(later) == labels(sample)))
+        .map(|(_, sample)| sample)
+        .collect();
+
+    let mut text = String::new();
+    for (name, kind, help, value) in METRICS {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} {}", name, kind);
+        for sample in &latest {
+            let _ = writeln!(
+                text,
+                "{}{{host=\"{}\",filename=\"{}\",savepath=\"{}\"}} {}",
+                name,
+                escape_label(&sample.host),
+                escape_label(&sample.filename),
+                escape_label(&sample.savepath),
+                value(sample)
+            );
+        }
+    }
+    text
+}
+
+/// Write `samples` to `path` for the node_exporter textfile collector. The
+/// collector may read at any moment, so the file is written next to `path`
+/// and renamed into place
+pub async fn write(path: &str, samples: &[Sample]) -> anyhow::Result<()> {
+    let tmp_path = format!("{}.{}.tmp", path, std::process::id());
+    tokio::fs::write(&tmp_path, render(samples))
+        .await
+        .map_err(|e| anyhow!("Failed to write metrics to {}: {}", tmp_path, e))?;
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .map_err(|e| anyhow!("Failed to rename {} to {}: {}", tmp_path, path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(savepath: &str, bytes: u64) -> Sample {
+        Sample {
+            host: "example.com".to_string(),
+            filename: "file.bin".to_string(),
+            savepath: savepath.to_string(),
+            bytes,
+            duration: Duration::from_millis(1500),
+            retries: 0,
+            threads: 4,
+        }
+    }
+
+    fn series(text: &str) -> Vec<&str> {
+        text.lines().filter(|line| line.starts_with("rget_bytes_downloaded_total{")).collect()
+    }
+
+    #[test]
+    fn tells_apart_files_of_the_same_name() {
+        let text = render(&[sample("a/file.bin", 1), sample("b/file.bin", 2)]);
+        assert_eq!(
+            series(&text),
+            [
+                "rget_bytes_downloaded_total{host=\"example.com\",filename=\"file.bin\",savepath=\"a/file.bin\"} 1",
+                "rget_bytes_downloaded_total{host=\"example.com\",filename=\"file.bin\",savepath=\"b/file.bin\"} 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_last_download_of_a_file() {
+        let text = render(&[sample("file.bin", 1), sample("other.bin", 2), sample("file.bin", 3)]);
+        let series = series(&text);
+        assert_eq!(series.len(), 2, "{}", text);
+        assert!(series[0].ends_with("savepath=\"other.bin\"} 2"), "{}", text);
+        assert!(series[1].ends_with("savepath=\"file.bin\"} 3"), "{}", text);
+        assert_eq!(text.matches("rget_threads{").count(), 2, "{}", text);
+    }
+}
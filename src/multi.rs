@@ -0,0 +1,459 @@
+use crate::options::MIN_SEGMENT_SIZE;
+use crate::{DownloadMode, DownloadOptions, Transfer, client, display_url, output, state};
+use crate::errors::{
+    INTERRUPT, RangeIgnored, file_error, http_error_with_body, is_range_refused, is_retryable, retry_delay,
+};
+use crate::output::{status, verbose, warning};
+use crate::probe::content_range_start;
+use crate::single::download_single_threaded;
+use crate::state::ResumeState;
+use anyhow::anyhow;
+use futures::StreamExt;
+use indicatif::ProgressBar;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::AbortOnDropHandle;
+
+/// Everything a chunk task needs, cloned into each spawned task
+#[derive(Clone)]
+struct ChunkContext {
+    client: reqwest::Client,
+    /// The URL followed by any mirrors serving the same file; chunks and
+    /// their retries rotate through them
+    urls: Arc<Vec<String>>,
+    savepath: String,
+    resume_state: Arc<tokio::sync::Mutex<ResumeState>>,
+    state_path: String,
+    progress_bar: ProgressBar,
+    /// Per-chunk bars for `--progress=per-thread`, hidden otherwise
+    chunk_bars: Arc<Vec<ProgressBar>>,
+    options: DownloadOptions,
+    /// Set when another chunk failed; tasks stop at the next await point
+    cancel: CancellationToken,
+    /// Bounds the number of chunks in flight
+    semaphore: Arc<tokio::sync::Semaphore>,
+    /// Largest range a request asks for, `u64::MAX` for no limit. Starts at
+    /// `--max-range-size` and shrinks for every chunk once the server turns
+    /// out to refuse or cut short larger ranges
+    range_cap: Arc<AtomicU64>,
+}
+
+impl ChunkContext {
+    /// Run `fut` unless the download gets cancelled first
+    async fn or_cancelled<T>(&self, thread_id: usize, fut: impl std::future::Future<Output = T>) -> anyhow::Result<T> {
+        tokio::select! {
+            value = fut => Ok(value),
+            _ = self.cancel.cancelled() => Err(anyhow!("Thread {}: cancelled", thread_id)),
+        }
+    }
+}
+
+/// What one chunk task did, for `--stats`
+struct ChunkStats {
+    /// Bytes fetched in this run, not counting what a resumed chunk already had
+    bytes: u64,
+    retries: u32,
+    /// From starting the chunk to finishing it, including retry delays
+    elapsed: Duration,
+    /// The URL the chunk finished on
+    url: String,
+}
+
+fn print_chunk_stats(stats: &[ChunkStats], show_urls: bool) {
+    status!("Thread statistics:");
+    for (i, chunk) in stats.iter().enumerate() {
+        let secs = chunk.elapsed.as_secs_f64();
+        let speed = if secs > 0.0 { (chunk.bytes as f64 / secs) as u64 } else { 0 };
+        let mut line = format!(
+            "  Thread {}: {} bytes in {:.2}s ({}/s), {} {}",
+            i,
+            chunk.bytes,
+            secs,
+            indicatif::HumanBytes(speed),
+            chunk.retries,
+            if chunk.retries == 1 { "retry" } else { "retries" }
+        );
+        if show_urls {
+            line.push_str(&format!(", from {}", display_url(&chunk.url)));
+        }
+        status!("{}", line);
+    }
+}
+
+/// 状态文件的最短写入间隔
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+pub async fn download_multi_threaded(
+    client: &reqwest::Client,
+    urls: &[String],
+    savepath: &str,
+    resume_state: ResumeState,
+    opts: &DownloadOptions,
+) -> anyhow::Result<Transfer> {
+    let total_size = resume_state.total_size;
+    let state_path = state::sidecar_path(savepath);
+    resume_state.save(&state_path).await?;
+
+    // 创建进度条
+    let progress_bar = output::download_bar(total_size);
+    progress_bar.set_position(resume_state.downloaded());
+    let chunk_progress: Vec<(u64, u64)> = resume_state.chunks.iter().map(|c| (c.len(), c.downloaded)).collect();
+    let chunk_bars = Arc::new(output::chunk_bars(&progress_bar, &chunk_progress));
+
+    // divide task into chunks
+    let mut handlers = Vec::new();
+    let num_chunks = resume_state.chunks.len();
+    let resume_state = Arc::new(tokio::sync::Mutex::new(resume_state));
+
+    // 续传时保留已有内容，全新下载时清空旧文件
+    let fresh = resume_state.lock().await.downloaded() == 0;
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .truncate(fresh)
+        .write(true)
+        .open(savepath)
+        .await?;
+
+    // 预先分配整个文件的空间，各线程只需用自己的句柄写入自己的区域
+    file.set_len(total_size)
+        .await
+        .map_err(|e| anyhow!("Failed to preallocate {} bytes for {}: {}", total_size, savepath, e))?;
+    drop(file);
+
+    let ctx = ChunkContext {
+        client: client.clone(),
+        urls: Arc::new(urls.to_vec()),
+        savepath: savepath.to_string(),
+        resume_state: resume_state.clone(),
+        state_path: state_path.clone(),
+        progress_bar: progress_bar.clone(),
+        chunk_bars: chunk_bars.clone(),
+        options: opts.clone(),
+        // Ctrl-C也会取消所有分块任务
+        cancel: INTERRUPT.child_token(),
+        semaphore: Arc::new(tokio::sync::Semaphore::new(opts.max_concurrent.max(1))),
+        range_cap: Arc::new(AtomicU64::new(opts.max_range_size.unwrap_or(u64::MAX))),
+    };
+
+    for i in 0..num_chunks {
+        let ctx = ctx.clone();
+
+        //
+        // 下载被整体超时打断时，drop掉的句柄会终止对应的任务
+        let handler = AbortOnDropHandle::new(tokio::task::spawn(async move {
+            // 拿到许可之后才开始下载，分块数和并发数可以不同
+            let _permit = ctx.or_cancelled(i, ctx.semaphore.clone().acquire_owned()).await??;
+            let started = Instant::now();
+            let (downloaded_before, start, end) = {
+                let state = ctx.resume_state.lock().await;
+                let chunk = &state.chunks[i];
+                (chunk.downloaded, chunk.start + chunk.downloaded, chunk.end)
+            };
+            output::log_line(output::LogLevel::Info, format_args!("Thread {} started on bytes {}-{}", i, start, end));
+
+            // 添加重试机制，每次重试都从已记录的进度继续
+            let retry = ctx.options.retry;
+            let mut attempt = 0;
+            let mut backoff = retry.backoff(i as u64);
+            loop {
+                // 每次重试换下一个镜像
+                let url = &ctx.urls[(i + attempt as usize) % ctx.urls.len()];
+                let result = download_chunk_with_retry(&ctx, i, url).await;
+                
+                match result {
+                    Ok(()) => {
+                        ctx.chunk_bars[i].finish();
+                        let downloaded_after = ctx.resume_state.lock().await.chunks[i].downloaded;
+                        return Ok(ChunkStats {
+                            bytes: downloaded_after - downloaded_before,
+                            retries: attempt,
+                            elapsed: started.elapsed(),
+                            url: url.clone(),
+                        });
+                    }
+                    // 服务器不支持分段时重试也没有用
+                    Err(e)
+                        if attempt < retry.retries
+                            && !e.is::<RangeIgnored>()
+                            && is_retryable(&e, &retry)
+                            && !ctx.cancel.is_cancelled() =>
+                    {
+                        attempt += 1;
+                        let (delay, note) = retry_delay(&e, &mut backoff, attempt);
+                        output::warning_above(&ctx.progress_bar, format!("Thread {} retry {}: {}{}", i, attempt, e, note));
+                        ctx.or_cancelled(i, tokio::time::sleep(delay)).await?;
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }));
+        
+        handlers.push(handler);
+    }
+
+    // 等待所有任务完成
+    let mut result = Ok(());
+    let mut stats = Vec::new();
+    let mut handlers = handlers.into_iter().enumerate();
+    for (i, handler) in handlers.by_ref() {
+        match handler.await {
+            // 其余线程还在下载，直接打印会和进度条交错在一起
+            Ok(Ok(chunk_stats)) => {
+                output::status_above(&progress_bar, format!("Thread {} completed successfully", i));
+                stats.push(chunk_stats);
+            }
+            Ok(Err(e)) if e.is::<RangeIgnored>() => {
+                result = Err(e);
+                break;
+            }
+            // 用context包一层，外面还能认出原来的错误类型
+            Ok(Err(e)) => {
+                let message = format!("Thread {} failed: {}", i, e);
+                result = Err(e.context(message));
+                break;
+            }
+            Err(e) => {
+                result = Err(anyhow!("Thread {} panicked: {}", i, e));
+                break;
+            }
+        }
+    }
+
+    // 出错后通知其余线程停止，并等它们把各自的数据刷到磁盘后退出，
+    // 这样之后文件就不会再被改动
+    ctx.cancel.cancel();
+    for (_, handler) in handlers {
+        let _ = handler.await;
+    }
+
+    if let Err(e) = result {
+        for bar in chunk_bars.iter() {
+            bar.abandon();
+        }
+        progress_bar.abandon();
+
+        if e.is::<RangeIgnored>() {
+            warning!("Warning: server ignored range requests, falling back to single-threaded download");
+            tokio::fs::remove_file(&state_path).await?;
+            return download_single_threaded(client, &urls[0], savepath, 0, opts).await;
+        }
+
+        // 失败时保存最新进度，以便之后用--continue继续；磁盘满时这一步也可能失败，
+        // 这时要报告的仍然是原来的错误
+        if let Err(save_err) = resume_state.lock().await.save(&state_path).await {
+            warning!("Warning: failed to save {}: {}", state_path, save_err);
+        }
+        return Err(e);
+    }
+
+    // 校验每个分块和最终文件的大小
+    for (i, chunk) in resume_state.lock().await.chunks.iter().enumerate() {
+        if chunk.downloaded != chunk.len() {
+            return Err(anyhow!(
+                "Thread {} came up short: expected {} bytes for range {}-{}, got {}",
+                i,
+                chunk.len(),
+                chunk.start,
+                chunk.end,
+                chunk.downloaded
+            ));
+        }
+    }
+    let actual_size = tokio::fs::metadata(savepath).await?.len();
+    if actual_size != total_size {
+        return Err(anyhow!(
+            "File size mismatch: expected {} bytes, got {}",
+            total_size,
+            actual_size
+        ));
+    }
+
+    output::finish_download_bar(&progress_bar);
+    status!("All downloads completed successfully");
+    if opts.stats {
+        print_chunk_stats(&stats, urls.len() > 1);
+    }
+    Ok(Transfer {
+        mode: DownloadMode::Multi { threads: num_chunks },
+        total_bytes: total_size,
+        retries: stats.iter().map(|s| s.retries).sum(),
+        digest: None,
+    })
+}
+
+async fn download_chunk_with_retry(ctx: &ChunkContext, thread_id: usize, url: &str) -> anyhow::Result<()> {
+    loop {
+        let (start, end) = {
+            let state = ctx.resume_state.lock().await;
+            let chunk = &state.chunks[thread_id];
+            if chunk.is_complete() {
+                return Ok(());
+            }
+            (chunk.start + chunk.downloaded, chunk.end)
+        };
+
+        // 分块太大时分成几次请求，每次不超过当前的上限
+        let cap = ctx.range_cap.load(Ordering::Relaxed);
+        let request_end = end.min(start.saturating_add(cap - 1));
+        let requested = request_end - start + 1;
+        match download_range(ctx, thread_id, url, start, request_end).await {
+            Ok(0) => {
+                return Err(anyhow!("Thread {}: server sent no data for bytes {}-{}", thread_id, start, request_end));
+            }
+            // 响应提前正常结束，说明服务器最多只给这么多，剩下的部分之后都按这个大小请求
+            Ok(received) if received < requested => {
+                let smaller = received.max(MIN_SEGMENT_SIZE);
+                if ctx.range_cap.fetch_min(smaller, Ordering::Relaxed) > smaller {
+                    output::warning_above(
+                        &ctx.progress_bar,
+                        format!(
+                            "Thread {}: server sent {} of {} expected bytes for the chunk, \
+                             fetching the remaining {} in requests of at most {} bytes",
+                            thread_id,
+                            received,
+                            requested,
+                            requested - received,
+                            smaller
+                        ),
+                    );
+                } else {
+                    verbose!(
+                        "Thread {}: server sent {} of {} expected bytes, fetching the rest",
+                        thread_id,
+                        received,
+                        requested
+                    );
+                }
+            }
+            Ok(_) => {}
+            // 416说明这个范围太大了，减半再试
+            Err(e) if is_range_refused(&e) && requested > MIN_SEGMENT_SIZE => {
+                let smaller = (requested / 2).max(MIN_SEGMENT_SIZE);
+                ctx.range_cap.fetch_min(smaller, Ordering::Relaxed);
+                output::warning_above(
+                    &ctx.progress_bar,
+                    format!("Thread {}: server refused a {}-byte range, trying {} bytes", thread_id, requested, smaller),
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// GET bytes `start..=end` and write them at `start`, returning how many
+/// bytes the server sent before ending the response
+async fn download_range(ctx: &ChunkContext, thread_id: usize, url: &str, start: u64, end: u64) -> anyhow::Result<u64> {
+    let range_header = format!("bytes={}-{}", start, end);
+    let request = ctx.client.get(url).header(reqwest::header::RANGE, range_header);
+    let resp = ctx
+        .or_cancelled(thread_id, client::send(request))
+        .await?
+        .map_err(|e| anyhow!("Thread {}: Network error: {}", thread_id, e))?;
+
+    // 返回200说明服务器忽略了Range，响应里是整个文件
+    if resp.status() == reqwest::StatusCode::OK {
+        return Err(RangeIgnored.into());
+    }
+
+    if !resp.status().is_success() && resp.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        return Err(http_error_with_body(resp, format!("Thread {}: Server error", thread_id), false).await);
+    }
+    // 数据要写在start处，服务器给的范围不是从这里开始就会写乱文件
+    if content_range_start(resp.headers()) != Some(start) {
+        let content_range = resp.headers().get(reqwest::header::CONTENT_RANGE).and_then(|v| v.to_str().ok());
+        return Err(anyhow!(
+            "Thread {}: asked for bytes {}-{}, but the server sent Content-Range: {}",
+            thread_id,
+            start,
+            end,
+            content_range.unwrap_or("(none)")
+        ));
+    }
+
+    // 每个线程使用独立的文件句柄，写入互不阻塞
+    let mut stream = resp.bytes_stream();
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(&ctx.savepath)
+        .await
+        .map_err(|e| file_error(e, &ctx.savepath, format!("Thread {}: File open error", thread_id)))?;
+    
+    // 定位到正确的位置
+    tokio::io::AsyncSeekExt::seek(&mut file, std::io::SeekFrom::Start(start))
+        .await
+        .map_err(|e| anyhow!("Thread {}: File seek error: {}", thread_id, e))?;
+    
+    // 已写入但尚未刷盘、还没记入状态的字节数
+    let mut unsaved = 0u64;
+    let mut received = 0u64;
+    let mut last_saved = Instant::now();
+    let expected = end - start + 1;
+    let result = async {
+        while received < expected
+            && let Some(chunk) = ctx.or_cancelled(thread_id, stream.next()).await?
+        {
+            // 连接中途断开和写盘失败分开报告，重试时只补下载缺少的部分
+            let chunk = chunk.map_err(|e| {
+                anyhow!(
+                    "Thread {}: server sent {} of {} expected bytes for bytes {}-{}: {}",
+                    thread_id,
+                    received,
+                    expected,
+                    start,
+                    end,
+                    e
+                )
+            })?;
+            // 多出来的字节属于下一个分块，不能写进去
+            let chunk = if received + chunk.len() as u64 > expected {
+                output::warning_above(
+                    &ctx.progress_bar,
+                    format!("Thread {}: server sent more than bytes {}-{}, ignoring the rest", thread_id, start, end),
+                );
+                chunk.slice(..(expected - received) as usize)
+            } else {
+                chunk
+            };
+            
+            tokio::io::AsyncWriteExt::write_all(&mut file, &chunk)
+                .await
+                .map_err(|e| file_error(e, &ctx.savepath, format!("Thread {}: File write error", thread_id)))?;
+            unsaved += chunk.len() as u64;
+            received += chunk.len() as u64;
+            
+            ctx.progress_bar.inc(chunk.len() as u64);
+            ctx.chunk_bars[thread_id].inc(chunk.len() as u64);
+            if let Some(ref limiter) = ctx.options.rate_limiter {
+                ctx.or_cancelled(thread_id, limiter.consume(chunk.len() as u64)).await?;
+            }
+            ctx.options.charge_quota(chunk.len() as u64)?;
+
+            // 定期刷盘并写入状态文件，状态里只记录已经落盘的进度
+            if last_saved.elapsed() >= STATE_SAVE_INTERVAL {
+                tokio::io::AsyncWriteExt::flush(&mut file)
+                    .await
+                    .map_err(|e| file_error(e, &ctx.savepath, format!("Thread {}: File write error", thread_id)))?;
+                let mut state = ctx.resume_state.lock().await;
+                state.chunks[thread_id].downloaded += std::mem::take(&mut unsaved);
+                state
+                    .save(&ctx.state_path)
+                    .await
+                    .map_err(|e| anyhow!("Thread {}: State file error: {}", thread_id, e))?;
+                last_saved = Instant::now();
+            }
+        }
+        Ok::<(), anyhow::Error>(())
+    }
+    .await;
+
+    // 无论成功与否都先把数据刷到磁盘，再把这部分进度记入状态
+    tokio::io::AsyncWriteExt::flush(&mut file)
+        .await
+        .map_err(|e| file_error(e, &ctx.savepath, format!("Thread {}: File write error", thread_id)))?;
+    ctx.resume_state.lock().await.chunks[thread_id].downloaded += unsaved;
+
+    result.map(|()| received)
+}
@@ -0,0 +1,602 @@
+use indicatif::style::ProgressTracker;
+use indicatif::{
+    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle, WeakProgressBar,
+};
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::time::{Duration, Instant};
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only errors
+    Quiet,
+    /// Status messages, warnings and the progress bar
+    Normal,
+    /// Also request and response headers (`-v`)
+    Verbose,
+    /// Reserved for body-level tracing (`-vv`)
+    Trace,
+}
+
+// 库默认什么都不打印，命令行在启动时设置
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Quiet as u8);
+
+/// How `--progress` displays a download
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressMode {
+    /// One bar per thread plus an overall bar
+    PerThread,
+    /// A single bar for the whole download
+    Aggregate,
+    /// No progress bars
+    None,
+    /// `PROGRESS <downloaded> <total> <bytes/s>` lines on stderr, for other programs to parse
+    #[value(alias = "dot")]
+    Machine,
+}
+
+static PROGRESS_MODE: AtomicU8 = AtomicU8::new(ProgressMode::None as u8);
+
+static SPEED_WINDOW_SECS: AtomicU64 = AtomicU64::new(5);
+
+/// The overall bar's format unless `--progress-template` replaces it.
+/// `{smooth_speed}` and `{smooth_eta}` are rget's own keys, see `RollingSpeed`
+pub const DEFAULT_PROGRESS_TEMPLATE: &str =
+    "[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} ({smooth_speed}, ETA {smooth_eta})";
+
+static PROGRESS_TEMPLATE: OnceLock<String> = OnceLock::new();
+
+static PROGRESS_FILE: OnceLock<String> = OnceLock::new();
+
+/// The per-thread bars of the current download, for `--progress-file`
+static THREAD_BARS: Mutex<Vec<WeakProgressBar>> = Mutex::new(Vec::new());
+
+// 写不进去时只警告一次
+static PROGRESS_FILE_FAILED: AtomicBool = AtomicBool::new(false);
+
+// 下载内容写到stdout时，状态信息改为输出到stderr
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        2 => Verbosity::Verbose,
+        _ => Verbosity::Trace,
+    }
+}
+
+pub fn set_progress_mode(mode: ProgressMode) {
+    PROGRESS_MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+pub fn progress_mode() -> ProgressMode {
+    match PROGRESS_MODE.load(Ordering::Relaxed) {
+        0 => ProgressMode::PerThread,
+        1 => ProgressMode::Aggregate,
+        2 => ProgressMode::None,
+        _ => ProgressMode::Machine,
+    }
+}
+
+/// How far back `--speed-window` averages the speed shown on progress bars
+pub fn set_speed_window(secs: u64) {
+    SPEED_WINDOW_SECS.store(secs, Ordering::Relaxed);
+}
+
+fn speed_window() -> Duration {
+    Duration::from_secs(SPEED_WINDOW_SECS.load(Ordering::Relaxed))
+}
+
+/// Use `template` for the overall bar, after checking indicatif accepts it
+pub fn set_progress_template(template: &str) -> anyhow::Result<()> {
+    bar_style(template).map_err(|e| anyhow::anyhow!("Invalid --progress-template '{}': {}", template, e))?;
+    let _ = PROGRESS_TEMPLATE.set(template.to_string());
+    Ok(())
+}
+
+/// Keep `path` updated with a JSON snapshot of every download's progress
+pub fn set_progress_file(path: &str) {
+    let _ = PROGRESS_FILE.set(path.to_string());
+}
+
+fn progress_template() -> &'static str {
+    PROGRESS_TEMPLATE.get().map_or(DEFAULT_PROGRESS_TEMPLATE, String::as_str)
+}
+
+fn bar_style(template: &str) -> Result<ProgressStyle, indicatif::style::TemplateError> {
+    Ok(ProgressStyle::default_bar()
+        .template(template)?
+        .with_key("smooth_speed", RollingSpeed::new(RollingField::Speed))
+        .with_key("smooth_eta", RollingSpeed::new(RollingField::Eta))
+        .progress_chars("##-"))
+}
+
+pub fn set_status_to_stderr(enabled: bool) {
+    STATUS_TO_STDERR.store(enabled, Ordering::Relaxed);
+}
+
+pub fn status_to_stderr() -> bool {
+    STATUS_TO_STDERR.load(Ordering::Relaxed)
+}
+
+/// How often a non-terminal stderr gets a one-line progress update
+const PLAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often `--progress=machine` prints a line
+const MACHINE_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often `--progress-file` is rewritten
+const PROGRESS_FILE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The progress bar shown while downloading `total_size` bytes. When stderr
+/// isn't a terminal the bar stays hidden and a plain percentage line is
+/// printed every few seconds instead, so logs don't fill with escape codes
+pub fn download_bar(total_size: u64) -> ProgressBar {
+    let progress_bar = terminal_bar(total_size);
+    if let Some(path) = PROGRESS_FILE.get() {
+        THREAD_BARS.lock().unwrap().clear();
+        spawn_progress_file(&progress_bar, path);
+    }
+    progress_bar
+}
+
+fn terminal_bar(total_size: u64) -> ProgressBar {
+    if progress_mode() == ProgressMode::Machine {
+        let progress_bar = ProgressBar::with_draw_target(Some(total_size), ProgressDrawTarget::hidden());
+        spawn_machine_progress(&progress_bar);
+        return progress_bar;
+    }
+    if !std::io::stderr().is_terminal() {
+        let progress_bar = ProgressBar::with_draw_target(Some(total_size), ProgressDrawTarget::hidden());
+        if verbosity() >= Verbosity::Normal && progress_mode() != ProgressMode::None {
+            spawn_plain_progress(&progress_bar);
+        }
+        return progress_bar;
+    }
+
+    let progress_bar = ProgressBar::new(total_size);
+    // 模板在启动时已经检查过
+    progress_bar.set_style(bar_style(progress_template()).unwrap());
+    apply_verbosity(&progress_bar);
+    progress_bar
+}
+
+fn spawn_plain_progress(progress_bar: &ProgressBar) {
+    // 只持有弱引用，进度条被丢弃后任务自己退出
+    let weak = progress_bar.downgrade();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(PLAIN_PROGRESS_INTERVAL).await;
+            let Some(progress_bar) = weak.upgrade() else {
+                break;
+            };
+            if progress_bar.is_finished() {
+                break;
+            }
+            let total = progress_bar.length().unwrap_or(0);
+            let position = progress_bar.position();
+            let percent = (position * 100).checked_div(total).unwrap_or(0);
+            eprintln!("{}% ({} of {} bytes)", percent, position, total);
+        }
+    });
+}
+
+/// Print a `PROGRESS` line for `progress_bar` every `MACHINE_PROGRESS_INTERVAL`
+/// until it finishes; the last line comes from `finish_download_bar`
+fn spawn_machine_progress(progress_bar: &ProgressBar) {
+    let weak = progress_bar.downgrade();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(MACHINE_PROGRESS_INTERVAL).await;
+            let Some(progress_bar) = weak.upgrade() else {
+                break;
+            };
+            if progress_bar.is_finished() {
+                break;
+            }
+            machine_progress_line(&progress_bar);
+        }
+    });
+}
+
+fn machine_progress_line(progress_bar: &ProgressBar) {
+    eprintln!(
+        "PROGRESS {} {} {}",
+        progress_bar.position(),
+        progress_bar.length().unwrap_or(0),
+        progress_bar.per_sec() as u64
+    );
+}
+
+/// Rewrite `path` with a snapshot of `progress_bar` every
+/// `PROGRESS_FILE_INTERVAL` until it finishes
+fn spawn_progress_file(progress_bar: &ProgressBar, path: &'static str) {
+    let weak = progress_bar.downgrade();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(PROGRESS_FILE_INTERVAL).await;
+            let Some(progress_bar) = weak.upgrade() else {
+                break;
+            };
+            if progress_bar.is_finished() {
+                break;
+            }
+            let snapshot = progress_snapshot(&progress_bar);
+            // 写命名管道时可能要等读的一方，放到阻塞线程里
+            let _ = tokio::task::spawn_blocking(move || write_progress_file(path, &snapshot)).await;
+        }
+    });
+}
+
+/// The overall and per-thread progress as one line of JSON
+fn progress_snapshot(progress_bar: &ProgressBar) -> String {
+    let threads: Vec<_> = THREAD_BARS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|bar| bar.upgrade())
+        .enumerate()
+        .map(|(i, bar)| {
+            serde_json::json!({
+                "thread": i,
+                "downloaded": bar.position(),
+                "total": bar.length(),
+                "done": bar.is_finished() || Some(bar.position()) == bar.length(),
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "downloaded": progress_bar.position(),
+        "total": progress_bar.length(),
+        "bytes_per_second": progress_bar.per_sec() as u64,
+        "elapsed_seconds": progress_bar.elapsed().as_secs_f64(),
+        "done": progress_bar.is_finished(),
+        "threads": threads,
+    })
+    .to_string()
+}
+
+/// Replace a regular file in one step, so a reader never sees half a
+/// snapshot; anything else, such as a named pipe, gets the line written to it
+fn write_progress_file(path: &str, snapshot: &str) {
+    use std::io::Write;
+
+    let regular = std::fs::metadata(path).map(|m| m.is_file()).unwrap_or(true);
+    let result = if regular {
+        let tmp_path = format!("{}.tmp", path);
+        std::fs::write(&tmp_path, format!("{}\n", snapshot)).and_then(|()| std::fs::rename(&tmp_path, path))
+    } else {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|mut pipe| writeln!(pipe, "{}", snapshot))
+    };
+    if let Err(e) = result
+        && !PROGRESS_FILE_FAILED.swap(true, Ordering::Relaxed)
+    {
+        warning!("Warning: failed to write --progress-file {}: {}", path, e);
+    }
+}
+
+/// Finish a bar from `download_bar` after a successful download
+pub fn finish_download_bar(progress_bar: &ProgressBar) {
+    progress_bar.finish_with_message("Download completed!");
+    // 定时任务可能还没来得及打印最后一行
+    if progress_mode() == ProgressMode::Machine {
+        machine_progress_line(progress_bar);
+    }
+    if let Some(path) = PROGRESS_FILE.get() {
+        let snapshot = progress_snapshot(progress_bar);
+        // 管道没人读时不能卡住下载的收尾
+        std::thread::spawn(move || write_progress_file(path, &snapshot));
+    }
+}
+
+/// Which value a `RollingSpeed` key prints
+#[derive(Clone, Copy)]
+enum RollingField {
+    Speed,
+    Eta,
+}
+
+/// Template key showing the speed averaged over the last `--speed-window`
+/// seconds, or the ETA derived from it. indicatif's own `{bytes_per_sec}`
+/// reacts to every burst, which makes it and `{eta}` jump around when chunks
+/// finish or the connection stalls
+#[derive(Clone)]
+struct RollingSpeed {
+    field: RollingField,
+    /// `(time, position)` samples, oldest first. The first one is the newest
+    /// sample from before the window, so the average covers the whole window
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RollingSpeed {
+    fn new(field: RollingField) -> Self {
+        RollingSpeed {
+            field,
+            samples: VecDeque::new(),
+        }
+    }
+
+    fn bytes_per_sec(&self, state: &ProgressState) -> f64 {
+        match (self.samples.front(), self.samples.back()) {
+            (Some(&(start, start_pos)), Some(&(end, end_pos))) if end > start => {
+                end_pos.saturating_sub(start_pos) as f64 / (end - start).as_secs_f64()
+            }
+            // 刚开始还没有足够的采样
+            _ => state.per_sec(),
+        }
+    }
+}
+
+impl ProgressTracker for RollingSpeed {
+    fn clone_box(&self) -> Box<dyn ProgressTracker> {
+        Box::new(self.clone())
+    }
+
+    fn tick(&mut self, state: &ProgressState, now: Instant) {
+        // 重试时位置可能会回退，这时之前的采样就没用了
+        if self.samples.back().is_some_and(|&(_, pos)| pos > state.pos()) {
+            self.samples.clear();
+        }
+        self.samples.push_back((now, state.pos()));
+        let window = speed_window();
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= window {
+            self.samples.pop_front();
+        }
+    }
+
+    fn reset(&mut self, _: &ProgressState, _: Instant) {
+        self.samples.clear();
+    }
+
+    fn write(&self, state: &ProgressState, w: &mut dyn std::fmt::Write) {
+        let speed = self.bytes_per_sec(state);
+        let _ = match self.field {
+            RollingField::Speed => write!(w, "{}/s", HumanBytes(speed as u64)),
+            RollingField::Eta => {
+                let remaining = state.len().unwrap_or(0).saturating_sub(state.pos());
+                if speed > 0.0 {
+                    write!(w, "{}", HumanDuration(Duration::from_secs_f64(remaining as f64 / speed)))
+                } else {
+                    write!(w, "unknown")
+                }
+            }
+        };
+    }
+}
+
+/// Bars for the chunks of a multi-threaded download, given each chunk's
+/// `(length, already downloaded)`. With `--progress=per-thread` they are
+/// stacked above the overall `progress_bar`; otherwise they are hidden
+pub fn chunk_bars(progress_bar: &ProgressBar, chunks: &[(u64, u64)]) -> Vec<ProgressBar> {
+    let bars = if progress_mode() != ProgressMode::PerThread || progress_bar.is_hidden() {
+        // 不显示也要记着进度，--progress-file要用
+        chunks
+            .iter()
+            .map(|&(len, downloaded)| {
+                let bar = ProgressBar::with_draw_target(Some(len), ProgressDrawTarget::hidden());
+                bar.set_position(downloaded);
+                bar
+            })
+            .collect()
+    } else {
+        visible_chunk_bars(progress_bar, chunks)
+    };
+    if PROGRESS_FILE.get().is_some() {
+        *THREAD_BARS.lock().unwrap() = bars.iter().map(ProgressBar::downgrade).collect();
+    }
+    bars
+}
+
+fn visible_chunk_bars(progress_bar: &ProgressBar, chunks: &[(u64, u64)]) -> Vec<ProgressBar> {
+    let multi = MultiProgress::new();
+    let bars = chunks
+        .iter()
+        .enumerate()
+        .map(|(i, &(len, downloaded))| {
+            let bar = multi.add(ProgressBar::new(len));
+            bar.set_style(
+                ProgressStyle::default_bar()
+                    .template("{prefix:>10} {bar:30.green/white} {bytes}/{total_bytes} ({smooth_speed})")
+                    .unwrap()
+                    .with_key("smooth_speed", RollingSpeed::new(RollingField::Speed))
+                    .progress_chars("##-")
+            );
+            bar.set_prefix(format!("Thread {}", i));
+            bar.set_position(downloaded);
+            bar
+        })
+        .collect();
+    // 总进度条放在最下面
+    multi.add(progress_bar.clone());
+    bars
+}
+
+/// Hide the progress bar when running quietly or with `--progress=none`
+pub fn apply_verbosity(progress_bar: &ProgressBar) {
+    if verbosity() < Verbosity::Normal || progress_mode() == ProgressMode::None {
+        progress_bar.set_draw_target(ProgressDrawTarget::hidden());
+    }
+}
+
+/// Print an informational message, keeping stdout clean when it carries the download
+macro_rules! status {
+    ($($arg:tt)*) => {{
+        let message = format!($($arg)*);
+        $crate::output::log_line($crate::output::LogLevel::Info, &message);
+        if $crate::output::verbosity() >= $crate::output::Verbosity::Normal {
+            if $crate::output::status_to_stderr() {
+                eprintln!("{}", message);
+            } else {
+                println!("{}", message);
+            }
+        }
+    }};
+}
+
+/// Print a non-fatal warning to stderr
+macro_rules! warning {
+    ($($arg:tt)*) => {{
+        let message = format!($($arg)*);
+        $crate::output::log_line($crate::output::LogLevel::Warn, &message);
+        if $crate::output::verbosity() >= $crate::output::Verbosity::Normal {
+            eprintln!("{}", message);
+        }
+    }};
+}
+
+/// Print debugging detail to stderr in `--verbose` mode
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::output::verbosity() >= $crate::output::Verbosity::Verbose {
+            let message = format!($($arg)*);
+            $crate::output::log_line($crate::output::LogLevel::Debug, &message);
+            eprintln!("{}", message);
+        }
+    };
+}
+
+/// Print a status line while `progress_bar` may be on screen. A visible bar
+/// redraws itself below the line; a hidden one (quiet, or stderr isn't a
+/// terminal) falls back to a plain `status!`
+pub fn status_above(progress_bar: &ProgressBar, message: impl Display) {
+    if progress_bar.is_hidden() {
+        status!("{}", message);
+        return;
+    }
+    log_line(LogLevel::Info, &message);
+    if verbosity() >= Verbosity::Normal {
+        progress_bar.println(message.to_string());
+    }
+}
+
+/// Like `status_above`, for warnings
+pub fn warning_above(progress_bar: &ProgressBar, message: impl Display) {
+    if progress_bar.is_hidden() {
+        warning!("{}", message);
+        return;
+    }
+    log_line(LogLevel::Warn, &message);
+    if verbosity() >= Verbosity::Normal {
+        progress_bar.println(message.to_string());
+    }
+}
+
+/// Severity of a `--log-file` entry
+#[derive(Clone, Copy, Debug)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    fn name(self) -> &'static str {
+        match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+        }
+    }
+}
+
+static LOG_FILE: OnceLock<Mutex<std::fs::File>> = OnceLock::new();
+
+/// Append timestamped entries to `path` from now on. Status messages, warnings
+/// and errors are logged even with `--quiet`; `--verbose` detail only with it
+pub fn set_log_file(path: &str) -> anyhow::Result<()> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("Failed to open --log-file {}: {}", path, e))?;
+    let _ = LOG_FILE.set(Mutex::new(file));
+    log_line(LogLevel::Info, format_args!("rget {} started", env!("CARGO_PKG_VERSION")));
+    Ok(())
+}
+
+/// Write one entry to the `--log-file`, if there is one
+pub fn log_line(level: LogLevel, message: impl Display) {
+    use std::io::Write;
+
+    let Some(file) = LOG_FILE.get() else {
+        return;
+    };
+    let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+    // 写日志失败不影响下载
+    let _ = writeln!(file, "{} {:<5} {}", timestamp(), level.name(), message);
+}
+
+/// The current time as RFC 3339 in UTC, e.g. `2024-05-01T12:34:56.789Z`
+fn timestamp() -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    format_timestamp(now)
+}
+
+/// `since_epoch` after 1970-01-01 as RFC 3339 in UTC
+fn format_timestamp(since_epoch: Duration) -> String {
+    let secs = since_epoch.as_secs();
+    let (hour, minute, second) = (secs / 3600 % 24, secs / 60 % 60, secs % 60);
+
+    // 把1970-01-01起的天数换算成公历日期
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        since_epoch.subsec_millis()
+    )
+}
+
+pub(crate) use status;
+pub(crate) use verbose;
+pub(crate) use warning;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_the_epoch() {
+        assert_eq!(format_timestamp(Duration::ZERO), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn formats_leap_days() {
+        assert_eq!(format_timestamp(Duration::from_millis(1_709_210_096_789)), "2024-02-29T12:34:56.789Z");
+        assert_eq!(format_timestamp(Duration::from_secs(951_868_799)), "2000-02-29T23:59:59.000Z");
+    }
+
+    #[test]
+    fn formats_dates_around_2000() {
+        assert_eq!(format_timestamp(Duration::from_secs(951_868_800)), "2000-03-01T00:00:00.000Z");
+        assert_eq!(format_timestamp(Duration::from_secs(946_684_799)), "1999-12-31T23:59:59.000Z");
+    }
+}
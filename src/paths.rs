@@ -0,0 +1,247 @@
+use crate::options::Options;
+use crate::{filename, state};
+use crate::output::{verbose, warning};
+use anyhow::anyhow;
+use url::Url;
+
+fn get_filename_from_url(url: &str) -> Option<String> {
+    let parsed_url = Url::parse(url).ok()?;
+    let segments: Vec<&str> = parsed_url.path_segments()?.collect();
+    segments.last().map(|s| s.to_string())
+}
+
+/// A savepath that names a directory to save into rather than a file
+pub async fn is_directory_target(savepath: &str) -> bool {
+    savepath.ends_with('/')
+        || savepath.ends_with(std::path::MAIN_SEPARATOR)
+        || tokio::fs::metadata(savepath).await.map(|m| m.is_dir()).unwrap_or(false)
+}
+
+/// Work out where to save `url`: the given savepath, or a name suggested by the
+/// server or derived from the URL, placed inside the savepath when that is a directory.
+/// Nothing is created on disk
+pub async fn resolve_savepath(savepath: Option<&str>, url: &str, suggested: Option<&str>) -> anyhow::Result<String> {
+    let derived_name = || -> anyhow::Result<String> {
+        if let Some(name) = suggested {
+            return Ok(name.to_string());
+        }
+        let name = get_filename_from_url(url).ok_or_else(|| anyhow!("Could not extract filename from URL"))?;
+        // URL以/结尾时没有文件名
+        Ok(filename::sanitize_filename(&name).unwrap_or_else(|| "index.html".to_string()))
+    };
+
+    let savepath = match savepath {
+        Some(p) if is_stdout(p) => return Ok(p.to_string()),
+        Some(p) => p,
+        None => return derived_name(),
+    };
+
+    if !is_directory_target(savepath).await {
+        return Ok(savepath.to_string());
+    }
+    Ok(std::path::Path::new(savepath)
+        .join(derived_name()?)
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// `-` as the savepath means stdout
+pub fn is_stdout(savepath: &str) -> bool {
+    savepath == "-"
+}
+
+/// Apply `--unique-names` and refuse to clobber an existing file, unless
+/// `may_exist` leaves that to a later [`refuse_existing`]
+pub async fn prepare_savepath(args: &Options, path: &str, may_exist: bool) -> anyhow::Result<String> {
+    let path = if args.unique_names {
+        unique_savepath(path).await
+    } else {
+        path.to_string()
+    };
+    let path = follow_symlink(&path).await;
+    if !may_exist {
+        refuse_existing(args, &path).await?;
+    }
+    check_writable(&path, args.no_part_file).await?;
+    Ok(path)
+}
+
+/// Fail when `path` exists and no option says what to do with it
+pub async fn refuse_existing(args: &Options, path: &str) -> anyhow::Result<()> {
+    // --timestamping和--if-modified-since要和已有的文件比较时间，之后决定是否覆盖
+    if !args.force
+        && !args.resume
+        && !args.timestamping
+        && args.if_modified_since.is_none()
+        && tokio::fs::try_exists(path).await.unwrap_or(false)
+    {
+        return Err(anyhow!(
+            "{}: file already exists, use --force to overwrite or --continue to resume",
+            path
+        ));
+    }
+    Ok(())
+}
+
+/// Symlinks followed at most before giving up on a chain of them
+const MAX_SYMLINKS: usize = 40;
+
+/// The file a symlink at `path` points to, through any chain of links, or
+/// `path` itself. The download then goes to the target instead of renaming
+/// over the link
+async fn follow_symlink(path: &str) -> String {
+    let mut current = std::path::PathBuf::from(path);
+    for _ in 0..MAX_SYMLINKS {
+        let is_link = tokio::fs::symlink_metadata(&current)
+            .await
+            .is_ok_and(|m| m.file_type().is_symlink());
+        let target = match tokio::fs::read_link(&current).await {
+            Ok(target) if is_link => target,
+            _ => break,
+        };
+        // 相对链接是相对于链接所在的目录
+        current = match current.parent() {
+            Some(dir) => dir.join(target),
+            None => target,
+        };
+    }
+    if current.as_os_str() != path {
+        verbose!("{} is a symlink, saving to {}", path, current.display());
+    }
+    current.to_string_lossy().into_owned()
+}
+
+/// Make sure a download can be saved at `path` before any request is sent:
+/// its directory must accept new files, and with `in_place` (no `.part`
+/// file) an existing `path` must open for writing
+async fn check_writable(path: &str, in_place: bool) -> anyhow::Result<()> {
+    let dir = std::path::Path::new(path)
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(std::path::Path::new("."));
+    check_dir_writable(dir, path).await?;
+    if in_place && tokio::fs::try_exists(path).await.unwrap_or(false) {
+        tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .await
+            .map_err(|e| cannot_write(path, e))?;
+    }
+    Ok(())
+}
+
+/// Create and remove a probe file in `dir`, reporting failure against `path`
+pub async fn check_dir_writable(dir: &std::path::Path, path: &str) -> anyhow::Result<()> {
+    let probe = dir.join(format!(".rget-write-test-{}", std::process::id()));
+    match tokio::fs::OpenOptions::new().write(true).create_new(true).open(&probe).await {
+        Ok(_) => {
+            let _ = tokio::fs::remove_file(&probe).await;
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) => Err(cannot_write(path, e)),
+    }
+}
+
+fn cannot_write(path: &str, e: std::io::Error) -> anyhow::Error {
+    let reason = match e.kind() {
+        std::io::ErrorKind::PermissionDenied => "permission denied".to_string(),
+        std::io::ErrorKind::ReadOnlyFilesystem => "read-only file system".to_string(),
+        std::io::ErrorKind::NotFound => "directory doesn't exist".to_string(),
+        _ => e.to_string(),
+    };
+    anyhow!("cannot write to {}: {}", path, reason)
+}
+
+/// Where to download `path` to: `<path>.part`, renamed once the download is
+/// verified so the final name never holds a half-written file, or `path`
+/// itself with `--no-part-file`. The `.part` file sits in the same directory,
+/// so the rename stays on one filesystem and is atomic
+pub async fn working_path(args: &Options, path: &str) -> anyhow::Result<String> {
+    if args.no_part_file {
+        return Ok(path.to_string());
+    }
+    let part = format!("{}.part", path);
+
+    // --continue时，没有.part但有同名文件，说明是直接写到最终文件名的旧下载
+    if args.resume
+        && !tokio::fs::try_exists(&part).await.unwrap_or(false)
+        && tokio::fs::try_exists(path).await.unwrap_or(false)
+    {
+        let renames = [
+            (path.to_string(), part.clone()),
+            (state::sidecar_path(path), state::sidecar_path(&part)),
+        ];
+        for (from, to) in renames {
+            if tokio::fs::try_exists(&from).await.unwrap_or(false) {
+                tokio::fs::rename(&from, &to)
+                    .await
+                    .map_err(|e| anyhow!("Failed to rename {} to {}: {}", from, to, e))?;
+            }
+        }
+    }
+    verbose!("Downloading to {} until the download is complete", part);
+    Ok(part)
+}
+
+/// Give `path` the server's `Last-Modified` time for `--timestamping`
+pub async fn set_modified_time(path: &str, time: std::time::SystemTime) -> anyhow::Result<()> {
+    let file = tokio::fs::File::options()
+        .write(true)
+        .open(path)
+        .await
+        .map_err(|e| anyhow!("Failed to open {}: {}", path, e))?
+        .into_std()
+        .await;
+    tokio::task::spawn_blocking(move || file.set_modified(time))
+        .await?
+        .map_err(|e| anyhow!("Failed to set the modification time of {}: {}", path, e))
+}
+
+pub async fn modified_time(path: &str) -> Option<std::time::SystemTime> {
+    tokio::fs::metadata(path).await.and_then(|m| m.modified()).ok()
+}
+
+/// After a failed download, delete the partial output and its `.rget` sidecar
+/// so a truncated file isn't mistaken for a complete one. `--keep-partial` and
+/// `--continue` keep them for resuming. `preexisting` is the modification time
+/// of a file that was already there; if the download never touched it, it stays
+pub async fn remove_partial(args: &Options, path: &str, preexisting: Option<std::time::SystemTime>) {
+    let current = modified_time(path).await;
+    if current.is_none() || (preexisting.is_some() && current == preexisting) {
+        return;
+    }
+    if args.keep_partial || args.resume {
+        warning!("Keeping partial download {}, resume it with --continue", path);
+        return;
+    }
+
+    let state_path = state::sidecar_path(path);
+    for file in [path, state_path.as_str()] {
+        if let Err(e) = tokio::fs::remove_file(file).await
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warning!("Warning: failed to remove partial download {}: {}", file, e);
+        }
+    }
+    verbose!("Removed partial download {}", path);
+}
+
+/// `--output-dir` as a savepath. It ends in a separator, so the download
+/// treats it as a directory and names the file from the URL or server
+pub fn output_dir_prefix(args: &Options) -> Option<String> {
+    args.output_dir
+        .as_ref()
+        .map(|dir| format!("{}{}", dir.trim_end_matches(['/', std::path::MAIN_SEPARATOR]), std::path::MAIN_SEPARATOR))
+}
+
+/// The first of `path`, `path.1`, `path.2`, ... that doesn't exist yet
+async fn unique_savepath(path: &str) -> String {
+    let mut candidate = path.to_string();
+    let mut n = 0u64;
+    while tokio::fs::try_exists(&candidate).await.unwrap_or(false) {
+        n += 1;
+        candidate = format!("{}.{}", path, n);
+    }
+    candidate
+}
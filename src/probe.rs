@@ -0,0 +1,331 @@
+use crate::options::Options;
+use crate::{Backend, client, display_url, etag, filename, output};
+use crate::errors::{NotModified, check_filesize, http_error_with_body};
+use crate::output::{verbose, warning};
+use crate::paths::modified_time;
+use anyhow::anyhow;
+use url::Url;
+
+/// What the HEAD probe learned about the remote file
+pub struct RemoteInfo {
+    /// URL to use for the actual download: where the probe was redirected to
+    /// on the same origin, or else the probed URL itself
+    pub url: String,
+    /// Where the probe ended up after all redirects, for reporting
+    pub final_url: String,
+    pub total_size: Option<u64>,
+    pub supports_ranges: bool,
+    pub content_type: Option<String>,
+    /// Name suggested by the `Content-Disposition` header
+    pub filename: Option<String>,
+    /// From the `Last-Modified` header, for `--timestamping`
+    pub last_modified: Option<std::time::SystemTime>,
+    pub etag: Option<String>,
+}
+
+/// Send a HEAD request for `url` and read what its headers say about the file.
+/// `conditions` are extra headers such as `If-Modified-Since`; a `304` reply to
+/// them fails with `NotModified`. When HEAD is refused, a 1-byte GET asks instead
+pub async fn probe(client: &reqwest::Client, url: &str, conditions: reqwest::header::HeaderMap) -> anyhow::Result<RemoteInfo> {
+    // 首先使用HEAD请求检查文件信息
+    let head_response = client::send(client.head(url).headers(conditions.clone())).await?;
+    output::log_line(
+        output::LogLevel::Info,
+        format_args!("HEAD {}: {}", display_url(url), head_response.status()),
+    );
+
+    if head_response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Err(NotModified.into());
+    }
+    // 有的服务器对HEAD回405或403，GET却能正常下载
+    if !head_response.status().is_success() {
+        verbose!("HEAD failed with {}, asking with a 1-byte GET instead", head_response.status());
+        return probe_with_get(client, url, conditions).await;
+    }
+
+    let final_url = redirected_url(head_response.url(), url);
+    let url = request_url(&final_url, url);
+    let headers = head_response.headers();
+
+    // 尝试获取Content-Length
+    let total_size = content_length(headers);
+
+    // 检查服务器是否支持范围请求
+    let supports_ranges = headers
+        .get(reqwest::header::ACCEPT_RANGES)
+        .map(|v| v.to_str().unwrap_or("").contains("bytes"))
+        .unwrap_or(false);
+
+    // 支持分段却不给Content-Length时（比如分块传输编码），从一字节请求的Content-Range里拿总大小
+    let total_size = match total_size {
+        None if supports_ranges => {
+            verbose!("Server supports ranges but sent no Content-Length, asking for the size with a 1-byte range");
+            let size = probe_range_size(client, &url).await;
+            if size.is_none() {
+                verbose!("No size in Content-Range, falling back to a single-threaded download");
+            }
+            size
+        }
+        total_size => total_size,
+    };
+
+    Ok(remote_info(url, final_url, total_size, supports_ranges, headers))
+}
+
+/// The probe for servers that refuse HEAD: `GET` with `Range: bytes=0-0`. A
+/// `206` reply proves ranges work and has the size in `Content-Range`; a `200`
+/// is the whole file, of which only the headers are read
+async fn probe_with_get(
+    client: &reqwest::Client,
+    url: &str,
+    conditions: reqwest::header::HeaderMap,
+) -> anyhow::Result<RemoteInfo> {
+    let request = client.get(url).headers(conditions).header(reqwest::header::RANGE, "bytes=0-0");
+    let response = client::send(request).await?;
+    output::log_line(
+        output::LogLevel::Info,
+        format_args!("GET {} (probe): {}", display_url(url), response.status()),
+    );
+
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Err(NotModified.into());
+    }
+    // 空文件连第一个字节都没有，服务器回416和Content-Range: bytes */0
+    if response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE
+        && content_range_total(response.headers()) == Some(0)
+    {
+        let final_url = redirected_url(response.url(), url);
+        return Ok(remote_info(request_url(&final_url, url), final_url, Some(0), true, response.headers()));
+    }
+    if !response.status().is_success() {
+        return Err(http_error_with_body(response, "Failed to connect to server", false).await);
+    }
+
+    let final_url = redirected_url(response.url(), url);
+    let url = request_url(&final_url, url);
+    let headers = response.headers();
+    let (total_size, supports_ranges) = if response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+        (content_range_total(headers), true)
+    } else {
+        (content_length(headers), false)
+    };
+    Ok(remote_info(url, final_url, total_size, supports_ranges, headers))
+}
+
+/// Where `url` redirected to, if anywhere
+fn redirected_url(response_url: &Url, url: &str) -> String {
+    let final_url = response_url.to_string();
+    if final_url != display_url(url) {
+        verbose!("Redirected to {}", final_url);
+        final_url
+    } else {
+        url.to_string()
+    }
+}
+
+/// The URL later requests should go to after `url` redirected to `final_url`.
+/// Requests sent straight to the final URL skip reqwest's redirect handling,
+/// which drops `Authorization` and `Cookie` when the host changes, so a
+/// redirect to another origin is followed again by every request instead
+fn request_url(final_url: &str, url: &str) -> String {
+    let origin = |u: &str| Url::parse(u).map(|u| u.origin());
+    match (origin(final_url), origin(url)) {
+        // 同源时直接用重定向后的地址，避免分块请求每次都再被重定向
+        (Ok(to), Ok(from)) if to == from => final_url.to_string(),
+        _ => {
+            if final_url != display_url(url) {
+                verbose!("{} is on another host, requesting {} each time", final_url, display_url(url));
+            }
+            url.to_string()
+        }
+    }
+}
+
+fn content_length(headers: &reqwest::header::HeaderMap) -> Option<u64> {
+    headers
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.parse::<u64>().ok())
+}
+
+/// The total size in `Content-Range: bytes 0-0/12345`; `*` means unknown
+fn content_range_total(headers: &reqwest::header::HeaderMap) -> Option<u64> {
+    headers
+        .get(reqwest::header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes ")?
+        .split_once('/')?
+        .1
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// The first byte in `Content-Range: bytes 100-199/12345`
+pub fn content_range_start(headers: &reqwest::header::HeaderMap) -> Option<u64> {
+    headers
+        .get(reqwest::header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes ")?
+        .split_once('-')?
+        .0
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// A `RemoteInfo` with the type, name, date and ETag taken from `headers`
+fn remote_info(
+    url: String,
+    final_url: String,
+    total_size: Option<u64>,
+    supports_ranges: bool,
+    headers: &reqwest::header::HeaderMap,
+) -> RemoteInfo {
+    let content_type = headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+
+    let filename = headers
+        .get(reqwest::header::CONTENT_DISPOSITION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(filename::from_content_disposition);
+
+    let last_modified = headers
+        .get(reqwest::header::LAST_MODIFIED)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| httpdate::parse_http_date(s).ok());
+
+    let etag = headers
+        .get(reqwest::header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+
+    RemoteInfo {
+        url,
+        final_url,
+        total_size,
+        supports_ranges,
+        content_type,
+        filename,
+        last_modified,
+        etag,
+    }
+}
+
+/// Ask for the first byte of `url` and read the total size from the
+/// `Content-Range` of the `206` reply
+async fn probe_range_size(client: &reqwest::Client, url: &str) -> Option<u64> {
+    let response = client::send(client.get(url).header(reqwest::header::RANGE, "bytes=0-0"))
+        .await
+        .ok()?;
+    if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        return None;
+    }
+    content_range_total(response.headers())
+}
+
+/// Compare the media type the server reported against `--expect-type`,
+/// ignoring parameters such as `; charset=utf-8`
+fn check_content_type(expected: &str, actual: Option<&str>) -> anyhow::Result<()> {
+    let actual = actual.ok_or_else(|| anyhow!("expected Content-Type {}, but the server didn't send one", expected))?;
+    let media_type = actual.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    let expected_lower = expected.trim().to_ascii_lowercase();
+
+    let matches = match expected_lower.strip_suffix("/*") {
+        Some(top_level) => media_type.split('/').next() == Some(top_level),
+        None => media_type == expected_lower,
+    };
+    if !matches {
+        return Err(anyhow!("unexpected Content-Type: expected {}, got {}", expected, actual));
+    }
+    Ok(())
+}
+
+/// HEAD `url` and apply `--expect-type` and `--max-filesize` before anything
+/// is written, so a login or error page isn't saved as the download
+pub async fn probe_expected(
+    args: &Options,
+    client: &reqwest::Client,
+    url: &str,
+    conditions: reqwest::header::HeaderMap,
+) -> anyhow::Result<RemoteInfo> {
+    let info = probe(client, url, conditions).await?;
+    verbose!("Content-Type: {}", info.content_type.as_deref().unwrap_or("unknown"));
+    if let Some(ref expected) = args.expect_type {
+        check_content_type(expected, info.content_type.as_deref())?;
+    }
+    if let Some(size) = info.total_size {
+        check_filesize(args.max_filesize, size, true)?;
+    }
+    Ok(info)
+}
+
+/// Probe the other mirrors for `--spread-mirrors`, keeping those that
+/// support ranges. Mirrors that disagree on the size can't be serving the
+/// same file, so that aborts the download
+pub async fn spread_pool(client: &reqwest::Client, info: &RemoteInfo, mirrors: &[&str]) -> anyhow::Result<Vec<String>> {
+    let mut pool = Vec::new();
+    for &mirror in mirrors {
+        if Backend::for_url(mirror) != Backend::Http {
+            warning!("Warning: only HTTP(S) mirrors can share chunks, skipping {}", display_url(mirror));
+            continue;
+        }
+        let mirror_info = match probe(client, mirror, Default::default()).await {
+            Ok(mirror_info) => mirror_info,
+            Err(e) => {
+                warning!("Warning: skipping mirror {}: {}", display_url(mirror), e);
+                continue;
+            }
+        };
+        if mirror_info.total_size != info.total_size {
+            return Err(anyhow!(
+                "Mirrors disagree on the file size: {} reports {}, {} reports {}",
+                display_url(&info.url),
+                info.total_size.map_or("no size".to_string(), |n| format!("{} bytes", n)),
+                display_url(mirror),
+                mirror_info.total_size.map_or("no size".to_string(), |n| format!("{} bytes", n)),
+            ));
+        }
+        if !mirror_info.supports_ranges {
+            warning!("Warning: mirror {} doesn't support range requests, skipping it", display_url(mirror));
+            continue;
+        }
+        pool.push(mirror_info.url);
+    }
+    Ok(pool)
+}
+
+/// Headers that make the probe of `url` conditional: `If-Modified-Since` with
+/// the date given to `--if-modified-since`, or else the modification time of
+/// `local`, and `If-None-Match` with the ETag cached when `local` was saved.
+/// `--force` wants the file downloaded again, so it drops the ETag
+pub async fn conditional_headers(args: &Options, url: &str, local: &str) -> reqwest::header::HeaderMap {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if !args.no_cache
+        && !args.force
+        && let Some(cache) = etag::EtagCache::load().await
+        && let Some(etag) = cache.lookup(&display_url(url), local).await
+    {
+        verbose!("Sending If-None-Match: {}", etag);
+        if let Ok(value) = reqwest::header::HeaderValue::from_str(etag) {
+            headers.insert(reqwest::header::IF_NONE_MATCH, value);
+        }
+    }
+    let since = match args.if_modified_since {
+        Some(Some(date)) => Some(date),
+        Some(None) => modified_time(local).await,
+        None => None,
+    };
+    if let Some(since) = since {
+        let date = httpdate::fmt_http_date(since);
+        verbose!("Sending If-Modified-Since: {}", date);
+        if let Ok(value) = reqwest::header::HeaderValue::from_str(&date) {
+            headers.insert(reqwest::header::IF_MODIFIED_SINCE, value);
+        }
+    }
+    headers
+}
@@ -0,0 +1,88 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often and how patiently a failed request is retried
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; 0 disables retrying
+    pub retries: u32,
+    /// Base delay, doubled with each retry
+    pub delay: Duration,
+    /// Upper bound for the exponential part of the delay
+    pub max_delay: Duration,
+    /// Fixed jitter seed for reproducible runs; random per run when `None`
+    pub seed: Option<u64>,
+    /// HTTP status codes worth retrying; any other error status fails at once
+    pub http_statuses: StatusSet,
+}
+
+/// HTTP status codes retried when `--retry-on-http` isn't given
+pub const DEFAULT_RETRY_STATUSES: [u16; 6] = [408, 429, 500, 502, 503, 504];
+
+/// A set of HTTP status codes, kept as a bitmap so `RetryPolicy` stays `Copy`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StatusSet([u64; 16]);
+
+impl StatusSet {
+    pub fn new(codes: &[u16]) -> Self {
+        let mut set = StatusSet::default();
+        for &code in codes {
+            if code < 1024 {
+                set.0[code as usize / 64] |= 1 << (code % 64);
+            }
+        }
+        set
+    }
+
+    pub fn contains(&self, code: u16) -> bool {
+        code < 1024 && self.0[code as usize / 64] & (1 << (code % 64)) != 0
+    }
+}
+
+impl RetryPolicy {
+    /// A backoff sequence for one retry loop; `stream` (e.g. the thread index)
+    /// keeps concurrent loops from drawing the same jitter
+    pub fn backoff(&self, stream: u64) -> Backoff {
+        let seed = self.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0)
+        });
+        Backoff {
+            policy: *self,
+            rng: seed ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15),
+        }
+    }
+}
+
+/// Exponential backoff with jitter: retry `n` waits `min(delay * 2^(n-1), max_delay)`
+/// plus a random extra of up to half that
+pub struct Backoff {
+    policy: RetryPolicy,
+    rng: u64,
+}
+
+impl Backoff {
+    /// Delay before retry number `retry` (starting at 1)
+    pub fn delay_for(&mut self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        let base = self.policy.delay.saturating_mul(factor).min(self.policy.max_delay);
+
+        let jitter_range = base.as_millis() as u64 / 2;
+        let jitter = if jitter_range > 0 {
+            self.next_random() % (jitter_range + 1)
+        } else {
+            0
+        };
+        base + Duration::from_millis(jitter)
+    }
+
+    // splitmix64，足够用来错开各线程的重试时间
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
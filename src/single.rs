@@ -0,0 +1,346 @@
+use crate::{DownloadMode, DownloadOptions, Transfer, client, hash, output};
+use crate::errors::{
+    Interrupted, RangeIgnored, check_filesize, file_error, http_error_with_body, interrupted, is_retryable, retry_delay,
+};
+use crate::output::{status, verbose, warning};
+use crate::paths::is_stdout;
+use crate::probe::{RemoteInfo, content_range_start};
+use anyhow::anyhow;
+use futures::StreamExt;
+
+/// The part of a file `--range` asks for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// From `start` to `end` inclusive, or to the end of the file
+    From { start: u64, end: Option<u64> },
+    /// The last `n` bytes
+    Last(u64),
+}
+
+impl ByteRange {
+    /// The `Range` header value for this range, skipping the first `offset`
+    /// bytes of it that are already downloaded
+    fn header(self, offset: u64) -> String {
+        match self {
+            ByteRange::From { start, end: Some(end) } => format!("bytes={}-{}", start + offset, end),
+            ByteRange::From { start, end: None } => format!("bytes={}-", start + offset),
+            ByteRange::Last(n) => format!("bytes=-{}", n - offset),
+        }
+    }
+
+    /// Turn the range into `From` with an end inside a file of `total_size` bytes
+    fn resolve(self, total_size: Option<u64>) -> anyhow::Result<ByteRange> {
+        let (start, end) = match (self, total_size) {
+            (ByteRange::From { start, end }, None) => return Ok(ByteRange::From { start, end }),
+            (ByteRange::Last(_), None) => {
+                return Err(anyhow!("--range -N needs the file size, which the server didn't send"));
+            }
+            (ByteRange::From { start, end }, Some(total)) => {
+                (start, end.unwrap_or(u64::MAX).min(total.saturating_sub(1)))
+            }
+            (ByteRange::Last(n), Some(total)) => (total.saturating_sub(n), total.saturating_sub(1)),
+        };
+        match total_size {
+            Some(total) if start >= total => Err(anyhow!("--range starts past the end of the {} byte file", total)),
+            _ => Ok(ByteRange::From { start, end: Some(end) }),
+        }
+    }
+
+    /// Bytes in the range, when that is known
+    fn len(self) -> Option<u64> {
+        match self {
+            ByteRange::From { start, end } => end.map(|end| end - start + 1),
+            ByteRange::Last(n) => Some(n),
+        }
+    }
+}
+
+/// `--range`: fetch part of the file into `savepath`, resuming within the
+/// range with `--continue`
+pub async fn download_byte_range(
+    client: &reqwest::Client,
+    url: &str,
+    savepath: &str,
+    info: &RemoteInfo,
+    range: ByteRange,
+    opts: &DownloadOptions,
+) -> anyhow::Result<Transfer> {
+    if !info.supports_ranges {
+        return Err(anyhow!("Server doesn't support range requests, can't download --range"));
+    }
+    let range = range.resolve(info.total_size)?;
+    if let ByteRange::From { start, end: Some(end) } = range {
+        status!("Downloading bytes {}-{} ({} bytes)", start, end, end - start + 1);
+    }
+
+    let existing_size = if opts.resume {
+        tokio::fs::metadata(savepath).await.map(|m| m.len()).unwrap_or(0)
+    } else {
+        0
+    };
+    let offset = match range.len() {
+        Some(len) if existing_size == len => {
+            status!("Range is already fully downloaded ({} bytes), skipping", len);
+            return Ok(Transfer {
+                mode: DownloadMode::Skipped,
+                total_bytes: len,
+                retries: 0,
+                digest: None,
+            });
+        }
+        Some(len) if existing_size > len => {
+            warning!("Warning: local file is larger than the range, restarting download");
+            0
+        }
+        _ if existing_size > 0 => {
+            status!("Resuming the range at byte {}", existing_size);
+            existing_size
+        }
+        _ => 0,
+    };
+
+    let opts = DownloadOptions {
+        byte_range: Some(range),
+        ..opts.clone()
+    };
+    download_single_threaded(client, url, savepath, offset, &opts).await
+}
+
+/// How much data before a resume point `--verify-resume` downloads again
+const VERIFY_RESUME_BYTES: u64 = 8 * 1024;
+
+/// Download up to `VERIFY_RESUME_BYTES` before `position` again, but no more
+/// than the `available` bytes already written there, and check that they
+/// match `savepath`. A torn write from an interrupted run shows up as a mismatch
+pub async fn verify_resume_point(
+    client: &reqwest::Client,
+    url: &str,
+    savepath: &str,
+    position: u64,
+    available: u64,
+) -> anyhow::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    let len = VERIFY_RESUME_BYTES.min(available).min(position);
+    if len == 0 {
+        return Ok(());
+    }
+    let start = position - len;
+    verbose!("Verifying bytes {}-{} before resuming", start, position - 1);
+    let range = format!("bytes={}-{}", start, position - 1);
+    let response = client::send(client.get(url).header(reqwest::header::RANGE, range)).await?;
+    if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        if !response.status().is_success() {
+            return Err(http_error_with_body(response, "Failed to verify the resume point", false).await);
+        }
+        return Err(anyhow!("Failed to verify the resume point: {}", RangeIgnored));
+    }
+    let remote = response.bytes().await?;
+
+    let mut local = vec![0u8; len as usize];
+    let mut file = tokio::fs::File::open(savepath)
+        .await
+        .map_err(|e| file_error(e, savepath, "File open error"))?;
+    file.seek(std::io::SeekFrom::Start(start))
+        .await
+        .map_err(|e| file_error(e, savepath, "File seek error"))?;
+    file.read_exact(&mut local)
+        .await
+        .map_err(|e| file_error(e, savepath, "File read error"))?;
+
+    if remote[..] != local[..] {
+        return Err(anyhow!(
+            "{} doesn't match the server in bytes {}-{}, the partial file looks corrupt; \
+             download it again with --force",
+            savepath,
+            start,
+            position - 1
+        ));
+    }
+    Ok(())
+}
+
+pub async fn download_single_threaded(
+    client: &reqwest::Client,
+    url: &str,
+    savepath: &str,
+    offset: u64,
+    opts: &DownloadOptions,
+) -> anyhow::Result<Transfer> {
+    let retry = opts.retry;
+    let mut position = offset;
+    // 从第一个字节开始下载时边写边算hash，省得下载完再读一遍文件
+    let mut hasher = None;
+    let mut attempt = 0;
+    let mut backoff = retry.backoff(0);
+    loop {
+        let offset = position;
+        match download_single_attempt(client, url, savepath, offset, &mut position, &mut hasher, opts).await {
+            Ok(()) => {
+                let digest = match (hasher, opts.hash_algorithm) {
+                    (Some(hasher), Some(algorithm)) => Some(hash::ExpectedHash {
+                        algorithm,
+                        hex: hasher.finalize_hex(),
+                    }),
+                    _ => None,
+                };
+                return Ok(Transfer {
+                    mode: DownloadMode::Single,
+                    total_bytes: position,
+                    retries: attempt,
+                    digest,
+                });
+            }
+            // stdout上已经输出的内容无法撤回，不能重试
+            // 磁盘满了之类的本地错误重试也没有用
+            Err(e) if attempt < retry.retries && !(is_stdout(savepath) && position > 0) && is_retryable(&e, &retry) => {
+                attempt += 1;
+                let (delay, note) = retry_delay(&e, &mut backoff, attempt);
+                warning!("Retry {}: {}{}", attempt, e, note);
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// One single-threaded GET starting at `offset`; `position` tracks how far the
+/// output got so that a retry can pick up from there. `hasher`, when set, has
+/// digested everything before `position`
+async fn download_single_attempt(
+    client: &reqwest::Client,
+    url: &str,
+    savepath: &str,
+    offset: u64,
+    position: &mut u64,
+    hasher: &mut Option<hash::Hasher>,
+    opts: &DownloadOptions,
+) -> anyhow::Result<()> {
+    let mut request = client.request(opts.method.clone(), url);
+    if let Some(ref body) = opts.body {
+        request = request.body(body.as_ref().clone());
+    }
+    if let Some(range) = opts.byte_range {
+        request = request.header(reqwest::header::RANGE, range.header(offset));
+    } else if offset > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+    }
+    let response = client::send(request).await?;
+
+    if !response.status().is_success() {
+        return Err(http_error_with_body(response, "Failed to download file", opts.content_on_error).await);
+    }
+    // 只要一部分时不能拿整个文件充数
+    if opts.byte_range.is_some() && response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        return Err(anyhow!("Server doesn't support range requests, can't download --range"));
+    }
+
+    // 服务器忽略了Range头，只能从头开始
+    let offset = if offset > 0 && response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        if is_stdout(savepath) {
+            return Err(anyhow!("Server ignored the range request, can't resume output to stdout"));
+        }
+        warning!("Warning: server ignored the range request, restarting download");
+        0
+    } else {
+        offset
+    };
+    // 续传的数据接在已有的字节后面，服务器给的范围必须正好从这里开始
+    if offset > 0 && opts.byte_range.is_none() && content_range_start(response.headers()) != Some(offset) {
+        let content_range = response.headers().get(reqwest::header::CONTENT_RANGE).and_then(|v| v.to_str().ok());
+        return Err(anyhow!(
+            "Can't resume at byte {}: the server sent Content-Range: {}",
+            offset,
+            content_range.unwrap_or("(none)")
+        ));
+    }
+    *position = offset;
+    // 续传的部分没有经过hasher，只能等下载完再读文件
+    if offset == 0 {
+        *hasher = opts.hash_algorithm.map(hash::Hasher::new);
+    }
+
+    let total_size = response
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.parse::<u64>().ok())
+        .map(|len| len + offset);
+    if let Some(size) = total_size {
+        check_filesize(opts.max_filesize, size, true)?;
+    }
+
+    let mut file: Box<dyn tokio::io::AsyncWrite + Unpin + Send> = if is_stdout(savepath) {
+        Box::new(tokio::io::stdout())
+    } else if offset > 0 {
+        let file = tokio::fs::OpenOptions::new().append(true).open(savepath).await;
+        Box::new(file.map_err(|e| file_error(e, savepath, "File open error"))?)
+    } else {
+        let file = tokio::fs::File::create(savepath).await;
+        Box::new(file.map_err(|e| file_error(e, savepath, "File create error"))?)
+    };
+    let mut stream = response.bytes_stream();
+
+    // 如果知道文件大小，显示进度条
+    let progress_bar = if let Some(size) = total_size {
+        let pb = output::download_bar(size);
+        pb.set_position(offset);
+        Some(pb)
+    } else {
+        status!("Downloading... (size unknown)");
+        None
+    };
+
+    let mut downloaded = 0u64;
+    let result = async {
+        loop {
+            let chunk = tokio::select! {
+                chunk = stream.next() => chunk,
+                _ = interrupted() => return Err(Interrupted.into()),
+            };
+            let Some(chunk) = chunk else {
+                break;
+            };
+            let chunk = chunk?;
+            // 没有Content-Length时只能边下载边检查
+            check_filesize(opts.max_filesize, *position + chunk.len() as u64, false)?;
+            tokio::io::AsyncWriteExt::write_all(&mut file, &chunk)
+                .await
+                .map_err(|e| file_error(e, savepath, "File write error"))?;
+            if let Some(ref mut hasher) = *hasher {
+                hasher.update(&chunk);
+            }
+            downloaded += chunk.len() as u64;
+            *position += chunk.len() as u64;
+            
+            if let Some(ref pb) = progress_bar {
+                pb.inc(chunk.len() as u64);
+            }
+            if let Some(ref limiter) = opts.rate_limiter {
+                limiter.consume(chunk.len() as u64).await;
+            }
+            opts.charge_quota(chunk.len() as u64)?;
+        }
+        Ok::<(), anyhow::Error>(())
+    }
+    .await;
+
+    // 出错时也要把已写入的数据刷到磁盘，重试时才能接着写
+    tokio::io::AsyncWriteExt::flush(&mut file)
+        .await
+        .map_err(|e| file_error(e, savepath, "File write error"))?;
+    if let Err(e) = result {
+        if let Some(pb) = progress_bar {
+            pb.abandon();
+        }
+        return Err(e);
+    }
+
+    if let Some(pb) = progress_bar {
+        output::finish_download_bar(&pb);
+    } else {
+        status!("Download completed! Downloaded {} bytes", downloaded);
+    }
+
+    Ok(())
+}
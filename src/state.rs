@@ -0,0 +1,159 @@
+use anyhow::anyhow;
+
+/// Progress of one chunk; `start..=end` is the assigned range and
+/// `downloaded` counts the bytes already written from `start`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkState {
+    pub start: u64,
+    pub end: u64,
+    pub downloaded: u64,
+}
+
+impl ChunkState {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.downloaded >= self.len()
+    }
+}
+
+/// Contents of the `<savepath>.rget` sidecar file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResumeState {
+    pub total_size: u64,
+    pub chunks: Vec<ChunkState>,
+}
+
+pub fn sidecar_path(savepath: &str) -> String {
+    format!("{}.rget", savepath)
+}
+
+impl ResumeState {
+    pub fn new(total_size: u64, ranges: &[(u64, u64)]) -> Self {
+        ResumeState {
+            total_size,
+            chunks: ranges
+                .iter()
+                .map(|&(start, end)| ChunkState { start, end, downloaded: 0 })
+                .collect(),
+        }
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.chunks.iter().map(|c| c.downloaded).sum()
+    }
+
+    fn parse(content: &str) -> anyhow::Result<Self> {
+        let mut total_size = None;
+        let mut chunks = Vec::new();
+
+        for line in content.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => continue,
+                ["total", n] => total_size = Some(n.parse()?),
+                ["chunk", start, end, downloaded] => chunks.push(ChunkState {
+                    start: start.parse()?,
+                    end: end.parse()?,
+                    downloaded: downloaded.parse()?,
+                }),
+                _ => return Err(anyhow!("Unrecognized line in state file: {}", line)),
+            }
+        }
+
+        let total_size = total_size.ok_or_else(|| anyhow!("State file is missing the total size"))?;
+        let state = ResumeState { total_size, chunks };
+        state.check()?;
+        Ok(state)
+    }
+
+    /// Reject a damaged or hand-edited state file: the chunks must cover
+    /// `0..total_size` in order without gaps or overlaps, and none may claim
+    /// more bytes than it holds
+    fn check(&self) -> anyhow::Result<()> {
+        let mut next = 0;
+        for c in &self.chunks {
+            if c.start != next || c.end < c.start {
+                return Err(anyhow!(
+                    "State file has a chunk {}-{} where one starting at {} was expected",
+                    c.start, c.end, next
+                ));
+            }
+            if c.downloaded > c.len() {
+                return Err(anyhow!(
+                    "State file claims {} bytes done in the {}-byte chunk at {}",
+                    c.downloaded, c.len(), c.start
+                ));
+            }
+            next = c.end + 1;
+        }
+        if next != self.total_size {
+            return Err(anyhow!("State file chunks cover {} of {} bytes", next, self.total_size));
+        }
+        Ok(())
+    }
+
+    pub async fn load(path: &str) -> anyhow::Result<Self> {
+        let content = tokio::fs::read_to_string(path).await?;
+        Self::parse(&content)
+    }
+
+    pub async fn save(&self, path: &str) -> anyhow::Result<()> {
+        let mut content = format!("total {}\n", self.total_size);
+        for c in &self.chunks {
+            content.push_str(&format!("chunk {} {} {}\n", c.start, c.end, c.downloaded));
+        }
+
+        // 先写临时文件再重命名，避免中途被杀掉时留下半个状态文件
+        let tmp_path = format!("{}.tmp", path);
+        tokio::fs::write(&tmp_path, content).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_what_save_writes() {
+        let state = ResumeState::parse("total 10\nchunk 0 4 5\nchunk 5 9 2\n").unwrap();
+        assert_eq!(state.total_size, 10);
+        assert_eq!(state.downloaded(), 7);
+        assert!(state.chunks[0].is_complete());
+    }
+
+    #[test]
+    fn rejects_reversed_chunks() {
+        assert!(ResumeState::parse("total 10\nchunk 0 4 0\nchunk 9 5 0\n").is_err());
+    }
+
+    #[test]
+    fn rejects_chunks_past_the_end() {
+        assert!(ResumeState::parse("total 10\nchunk 0 4 0\nchunk 5 14 0\n").is_err());
+    }
+
+    #[test]
+    fn rejects_overlapping_chunks() {
+        assert!(ResumeState::parse("total 10\nchunk 0 5 0\nchunk 5 9 0\n").is_err());
+    }
+
+    #[test]
+    fn rejects_gaps_between_chunks() {
+        assert!(ResumeState::parse("total 10\nchunk 0 3 0\nchunk 5 9 0\n").is_err());
+        assert!(ResumeState::parse("total 10\nchunk 0 4 0\n").is_err());
+    }
+
+    #[test]
+    fn rejects_unsorted_chunks() {
+        assert!(ResumeState::parse("total 10\nchunk 5 9 0\nchunk 0 4 0\n").is_err());
+    }
+
+    #[test]
+    fn rejects_more_downloaded_than_the_chunk_holds() {
+        assert!(ResumeState::parse("total 10\nchunk 0 4 6\nchunk 5 9 0\n").is_err());
+    }
+}
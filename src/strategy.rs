@@ -0,0 +1,194 @@
+/// Files smaller than this are downloaded single-threaded unless
+/// `--prefer-single-thread-below` says otherwise
+pub const MULTI_THREAD_MIN_SIZE: u64 = 1024 * 1024;
+
+/// With `--adaptive-threads`, each thread gets at least this many bytes, so a
+/// 20 MiB file uses 3 threads and anything up to 8 MiB uses one
+const ADAPTIVE_BYTES_PER_THREAD: u64 = 8 * 1024 * 1024;
+
+/// How a fresh download gets fetched
+#[derive(Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// No Content-Length, so one GET read until the body ends
+    UnknownSize,
+    /// Nothing to fetch, just create the file
+    Empty,
+    /// One GET for the whole file
+    Single { total_size: u64 },
+    /// One ranged GET per chunk
+    Multi { total_size: u64, ranges: Vec<(u64, u64)> },
+}
+
+/// Pick the `Strategy` for a file of `total_size` bytes. `segment_size`
+/// fixes the chunk size; otherwise there is one chunk per thread. Files
+/// smaller than `single_below` get one thread
+pub fn decide_strategy(
+    total_size: Option<u64>,
+    supports_ranges: bool,
+    threads: u8,
+    adaptive: bool,
+    segment_size: Option<u64>,
+    single_below: u64,
+) -> Strategy {
+    let total_size = match total_size {
+        None => return Strategy::UnknownSize,
+        // 空文件不需要再发GET，也避免按0字节去切分
+        Some(0) => return Strategy::Empty,
+        Some(total_size) => total_size,
+    };
+    let threads = if adaptive {
+        (threads as u64).min(total_size.div_ceil(ADAPTIVE_BYTES_PER_THREAD))
+    } else {
+        threads as u64
+    };
+    // 如果服务器不支持范围请求或者文件太小，就使用单线程下载
+    if !supports_ranges || total_size < single_below || threads <= 1 {
+        return Strategy::Single { total_size };
+    }
+    let ranges = match segment_size {
+        Some(segment_size) => split_segments(total_size, segment_size),
+        None => split_ranges(total_size, threads),
+    };
+    // 分段大小不小于文件时只有一段
+    if ranges.len() <= 1 {
+        return Strategy::Single { total_size };
+    }
+    Strategy::Multi { total_size, ranges }
+}
+
+/// Split `0..total_size` into inclusive ranges of `segment_size` bytes; the
+/// last one gets whatever is left
+pub fn split_segments(total_size: u64, segment_size: u64) -> Vec<(u64, u64)> {
+    let segment_size = segment_size.max(1);
+    (0..total_size)
+        .step_by(segment_size as usize)
+        .map(|start| (start, (start + segment_size).min(total_size) - 1))
+        .collect()
+}
+
+/// Split `0..total_size` into at most `parts` contiguous, non-empty inclusive ranges.
+/// The first `total_size % parts` ranges are one byte longer than the rest.
+pub fn split_ranges(total_size: u64, parts: u64) -> Vec<(u64, u64)> {
+    let parts = parts.clamp(1, total_size.max(1));
+    let base = total_size / parts;
+    let remainder = total_size % parts;
+
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    for i in 0..parts {
+        let len = base + u64::from(i < remainder);
+        if len == 0 {
+            break;
+        }
+        // HTTP Range是闭区间，所以end = start + len - 1
+        ranges.push((start, start + len - 1));
+        start += len;
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decide(total_size: Option<u64>, supports_ranges: bool, threads: u8) -> Strategy {
+        decide_strategy(total_size, supports_ranges, threads, false, None, MULTI_THREAD_MIN_SIZE)
+    }
+
+    #[test]
+    fn decides_by_size_threshold() {
+        let ranges = split_ranges(MULTI_THREAD_MIN_SIZE, 4);
+        assert_eq!(
+            decide(Some(MULTI_THREAD_MIN_SIZE), true, 4),
+            Strategy::Multi { total_size: MULTI_THREAD_MIN_SIZE, ranges }
+        );
+        assert_eq!(
+            decide(Some(MULTI_THREAD_MIN_SIZE - 1), true, 4),
+            Strategy::Single { total_size: MULTI_THREAD_MIN_SIZE - 1 }
+        );
+        // --prefer-single-thread-below 0让小文件也能分块
+        assert!(matches!(decide_strategy(Some(100), true, 4, false, None, 0), Strategy::Multi { .. }));
+    }
+
+    #[test]
+    fn decides_single_thread_without_threads_or_ranges() {
+        let total_size = 10 * MULTI_THREAD_MIN_SIZE;
+        assert_eq!(decide(Some(total_size), true, 1), Strategy::Single { total_size });
+        assert_eq!(decide(Some(total_size), true, 0), Strategy::Single { total_size });
+        assert_eq!(decide(Some(total_size), false, 8), Strategy::Single { total_size });
+    }
+
+    #[test]
+    fn decides_without_a_length() {
+        assert_eq!(decide(None, true, 8), Strategy::UnknownSize);
+        assert_eq!(decide(None, false, 1), Strategy::UnknownSize);
+        assert_eq!(decide(Some(0), true, 8), Strategy::Empty);
+    }
+
+    #[test]
+    fn decides_adaptive_and_segmented() {
+        // 20 MiB按每线程8 MiB算是3个线程
+        let total_size = 20 * 1024 * 1024;
+        match decide_strategy(Some(total_size), true, 8, true, None, MULTI_THREAD_MIN_SIZE) {
+            Strategy::Multi { ranges, .. } => assert_eq!(ranges.len(), 3),
+            other => panic!("expected a multi-threaded download, got {:?}", other),
+        }
+        assert_eq!(
+            decide_strategy(Some(total_size), true, 8, true, None, 0),
+            decide_strategy(Some(total_size), true, 3, false, None, 0)
+        );
+        let segmented = decide_strategy(Some(total_size), true, 2, false, Some(total_size / 10), 0);
+        assert!(matches!(segmented, Strategy::Multi { ref ranges, .. } if ranges.len() == 10));
+        // 一段就装得下整个文件时不用分块
+        assert_eq!(
+            decide_strategy(Some(total_size), true, 8, false, Some(total_size), 0),
+            Strategy::Single { total_size }
+        );
+    }
+
+    /// 区间必须从0开始首尾相接、不为空，并且正好覆盖到total_size
+    fn assert_covers(ranges: &[(u64, u64)], total_size: u64) {
+        let mut next = 0;
+        for &(start, end) in ranges {
+            assert_eq!(start, next, "gap or overlap in {:?}", ranges);
+            assert!(start <= end, "empty range in {:?}", ranges);
+            next = end + 1;
+        }
+        assert_eq!(next, total_size, "{:?} doesn't cover 0..{}", ranges, total_size);
+    }
+
+    #[test]
+    fn split_ranges_covers_the_file() {
+        for total_size in [1, 2, 6, 7, 8, 100, 1_000_003, 3 * 1024 * 1024] {
+            for parts in [1, 2, 3, 7, 8, 64, 255] {
+                let ranges = split_ranges(total_size, parts);
+                assert_covers(&ranges, total_size);
+                assert_eq!(ranges.len() as u64, parts.min(total_size));
+            }
+        }
+    }
+
+    #[test]
+    fn split_ranges_spreads_the_remainder() {
+        // 1_000_003 = 7 * 142_857 + 4，前4段多一个字节
+        let lengths: Vec<u64> = split_ranges(1_000_003, 7).iter().map(|(start, end)| end - start + 1).collect();
+        assert_eq!(lengths, [142_858, 142_858, 142_858, 142_858, 142_857, 142_857, 142_857]);
+    }
+
+    #[test]
+    fn split_ranges_handles_degenerate_input() {
+        assert_eq!(split_ranges(0, 4), []);
+        assert_eq!(split_ranges(10, 0), [(0, 9)]);
+        assert_eq!(split_ranges(3, 8), [(0, 0), (1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn split_segments_covers_the_file() {
+        for total_size in [1, 7, 100, 1_000_003] {
+            for segment_size in [0, 1, 3, 64, 1_000_000, 2_000_000] {
+                assert_covers(&split_segments(total_size, segment_size), total_size);
+            }
+        }
+        assert_eq!(split_segments(10, 4), [(0, 3), (4, 7), (8, 9)]);
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::time::{Duration, Instant};
+
+/// Caps the combined throughput of every task sharing it
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    // 已放行的字节按限速应当在这个时刻之前传完
+    next_free: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            bytes_per_sec: bytes_per_sec.max(1),
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Account for `bytes` just received, sleeping long enough to stay under the limit
+    pub async fn consume(&self, bytes: u64) {
+        let deadline = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let start = (*next_free).max(Instant::now());
+            *next_free = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+            *next_free
+        };
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+/// `--quota`: a byte budget shared by every download of a batch
+pub struct Quota {
+    limit: u64,
+    used: AtomicU64,
+}
+
+/// More was downloaded than `--quota` allows
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub limit: u64,
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "download quota of {} bytes exceeded", self.limit)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+impl Quota {
+    pub fn new(limit: u64) -> Self {
+        Quota {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    /// Account for `bytes` just received, failing once the total goes over the limit
+    pub fn consume(&self, bytes: u64) -> Result<(), QuotaExceeded> {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if used > self.limit {
+            return Err(QuotaExceeded { limit: self.limit });
+        }
+        Ok(())
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Whether there is nothing left to start another download with
+    pub fn is_exhausted(&self) -> bool {
+        self.used() >= self.limit
+    }
+}
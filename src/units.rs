@@ -0,0 +1,20 @@
+/// Parse a byte size such as `500`, `500k`, `2M` or `1.5G` (binary multiples)
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&value[..value.len() - 1], 1024u64),
+        Some('m') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('g') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        Some('t') => (&value[..value.len() - 1], 1024 * 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size '{}', expected e.g. 500k, 2M or 1G", value))?;
+    if !number.is_finite() || number < 0.0 {
+        return Err(format!("invalid size '{}'", value));
+    }
+    Ok((number * multiplier as f64) as u64)
+}
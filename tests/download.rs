@@ -0,0 +1,930 @@
+//! End-to-end downloads against a small HTTP server on a local port
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// 3 MiB, large enough for a multi-threaded download
+const SIZE: usize = 3 * 1024 * 1024;
+
+/// A byte pattern where every chunk looks different, so misplaced ranges show up
+fn pattern() -> Vec<u8> {
+    (0..SIZE).map(|i| (i % 251) as u8 ^ (i / 4096) as u8).collect()
+}
+
+/// How the mock server answers
+#[derive(Clone, Copy)]
+struct ServerOptions {
+    /// Honour `Range` and advertise `Accept-Ranges: bytes`
+    ranges: bool,
+    /// Send `Content-Length` on full responses; without it the size only
+    /// shows up in the `Content-Range` of ranged ones
+    content_length: bool,
+    /// Send full responses with `Transfer-Encoding: chunked` instead of `Content-Length`
+    chunked: bool,
+    /// Answer HEAD like GET; without it HEAD gets `405 Method Not Allowed`
+    head: bool,
+    /// Advertise `Accept-Ranges` but answer ranged requests with the whole body
+    ignore_range: bool,
+    /// Gzip full responses when the request's `Accept-Encoding` allows it
+    gzip: bool,
+    /// Send this `ETag` and answer a matching `If-None-Match` with `304 Not Modified`
+    etag: Option<&'static str>,
+    /// Answer everything with `302 Found` to the same path on this port
+    redirect_to: Option<u16>,
+    /// Send this many bytes past the end of each requested range
+    over_send: usize,
+    /// Start each ranged response this many bytes before the requested start
+    shift_range: usize,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            ranges: true,
+            content_length: true,
+            chunked: false,
+            head: true,
+            ignore_range: false,
+            gzip: false,
+            etag: None,
+            redirect_to: None,
+            over_send: 0,
+            shift_range: 0,
+        }
+    }
+}
+
+/// One request as the mock server saw it
+#[derive(Clone, Debug)]
+struct Request {
+    method: String,
+    /// The request target exactly as sent, a path or an absolute URL
+    target: String,
+    headers: Vec<(String, String)>,
+    /// The `Content-Length` bytes after the headers
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// The value of header `name`, matched case-insensitively
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+type RequestLog = Arc<Mutex<Vec<Request>>>;
+
+/// A mock server on a free local port
+struct Server {
+    port: u16,
+    /// TCP connections accepted so far
+    connections: Arc<AtomicUsize>,
+    /// Every request answered so far, in order
+    requests: RequestLog,
+}
+
+impl Server {
+    fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Serve `body` at any path. Absolute-form targets are answered the same
+/// way, so the server also stands in for a forward proxy
+fn serve(body: Vec<u8>, options: ServerOptions) -> Server {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let requests = RequestLog::default();
+    let counter = connections.clone();
+    let log = requests.clone();
+    let body = Arc::new(body);
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let body = body.clone();
+            let log = log.clone();
+            std::thread::spawn(move || {
+                if let Ok(reader) = stream.try_clone() {
+                    let _ = serve_connection(reader, &stream, &body, options, &log);
+                }
+            });
+        }
+    });
+    Server { port, connections, requests }
+}
+
+/// Serve `body` on a Unix socket at `path`
+#[cfg(unix)]
+fn serve_unix(body: Vec<u8>, options: ServerOptions, path: &std::path::Path) {
+    let listener = std::os::unix::net::UnixListener::bind(path).unwrap();
+    let body = Arc::new(body);
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let body = body.clone();
+            std::thread::spawn(move || {
+                if let Ok(reader) = stream.try_clone() {
+                    let _ = serve_connection(reader, &stream, &body, options, &RequestLog::default());
+                }
+            });
+        }
+    });
+}
+
+/// Answer requests read from `reader` on `stream` until the client hangs up
+fn serve_connection(
+    reader: impl Read,
+    mut stream: impl Write,
+    body: &[u8],
+    options: ServerOptions,
+    log: &RequestLog,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    while respond(&mut reader, &mut stream, body, options, log)? {}
+    Ok(())
+}
+
+/// Answer one request and add it to `log`, returning whether the connection stays open
+fn respond(
+    reader: &mut BufReader<impl Read>,
+    stream: &mut impl Write,
+    body: &[u8],
+    options: ServerOptions,
+    log: &RequestLog,
+) -> std::io::Result<bool> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line)? == 0 {
+        return Ok(false);
+    }
+    let mut parts = request_line.split_whitespace();
+    let mut request = Request {
+        method: parts.next().unwrap_or_default().to_string(),
+        target: parts.next().unwrap_or_default().to_string(),
+        headers: Vec::new(),
+        body: Vec::new(),
+    };
+    let head = request.method == "HEAD";
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            request.headers.push((name.to_string(), value.trim().to_string()));
+        }
+    }
+    if let Some(length) = request.header("content-length").and_then(|value| value.parse().ok()) {
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body)?;
+    }
+    let range = request.header("range").and_then(|value| parse_range(value, body.len()));
+    let accepts_gzip = request.header("accept-encoding").is_some_and(|value| value.contains("gzip"));
+    let not_modified = options.etag.is_some() && request.header("if-none-match") == options.etag;
+    let target = request.target.clone();
+    log.lock().unwrap().push(request);
+
+    let mut response = Vec::new();
+    if let Some(port) = options.redirect_to {
+        write!(response, "HTTP/1.1 302 Found\r\nLocation: http://127.0.0.1:{}{}\r\n", port, target)?;
+        write!(response, "Content-Length: 0\r\n\r\n")?;
+        stream.write_all(&response)?;
+        return Ok(true);
+    }
+    if not_modified {
+        write!(response, "HTTP/1.1 304 Not Modified\r\nContent-Length: 0\r\n\r\n")?;
+        stream.write_all(&response)?;
+        return Ok(true);
+    }
+    if head && !options.head {
+        write!(response, "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\nContent-Length: 0\r\n\r\n")?;
+        stream.write_all(&response)?;
+        return Ok(true);
+    }
+    let gzipped;
+    let (content, partial) = match range {
+        Some((start, end)) if options.ranges && !options.ignore_range => {
+            let start = start.saturating_sub(options.shift_range);
+            let end = (end + options.over_send).min(body.len() - 1);
+            write!(response, "HTTP/1.1 206 Partial Content\r\n")?;
+            write!(response, "Content-Range: bytes {}-{}/{}\r\n", start, end, body.len())?;
+            (&body[start..=end], true)
+        }
+        _ if options.gzip && accepts_gzip => {
+            write!(response, "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\n")?;
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            gzipped = encoder.finish()?;
+            (&gzipped[..], false)
+        }
+        _ => {
+            write!(response, "HTTP/1.1 200 OK\r\n")?;
+            (body, false)
+        }
+    };
+    if options.ranges {
+        write!(response, "Accept-Ranges: bytes\r\n")?;
+    }
+    if let Some(etag) = options.etag {
+        write!(response, "ETag: {}\r\n", etag)?;
+    }
+    let chunked = !partial && options.chunked;
+    // 没有Content-Length也没有分块编码时只能靠断开连接来结束响应
+    let keep_alive = partial || options.content_length || chunked;
+    if chunked {
+        write!(response, "Transfer-Encoding: chunked\r\n\r\n")?;
+    } else if keep_alive {
+        write!(response, "Content-Length: {}\r\n\r\n", content.len())?;
+    } else {
+        write!(response, "Connection: close\r\n\r\n")?;
+    }
+    if !head && chunked {
+        for piece in content.chunks(64 * 1024) {
+            write!(response, "{:x}\r\n", piece.len())?;
+            response.extend_from_slice(piece);
+            write!(response, "\r\n")?;
+        }
+        write!(response, "0\r\n\r\n")?;
+    } else if !head {
+        response.extend_from_slice(content);
+    }
+    stream.write_all(&response)?;
+    stream.flush()?;
+    Ok(keep_alive)
+}
+
+/// `bytes=a-b` or `bytes=a-`, as an inclusive range within `len`. Ranges
+/// that don't overlap the body, including any range of an empty one, get
+/// the whole body like a server that ignores them
+fn parse_range(value: &str, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let start = start.parse().ok()?;
+    let last = len.checked_sub(1)?;
+    let end = if end.is_empty() { last } else { end.parse::<usize>().ok()?.min(last) };
+    (start <= end).then_some((start, end))
+}
+
+/// A fresh, empty directory for one test's output
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rget-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Run rget without reading the user's `~/.rgetrc`
+fn rget(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rget"))
+        .arg("--no-config")
+        .arg("--no-progress")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Download with `threads` from a server configured by `options` and check the result
+fn download(name: &str, options: ServerOptions, threads: &str) -> String {
+    download_body(name, pattern(), options, &["-t", threads])
+}
+
+/// Download `body` with extra `args` and check the file matches it
+fn download_body(name: &str, body: Vec<u8>, options: ServerOptions, args: &[&str]) -> String {
+    let port = serve(body.clone(), options).port;
+    let dir = temp_dir(name);
+    let savepath = dir.join("out.bin");
+
+    let url = format!("http://127.0.0.1:{}/file.bin", port);
+    let hash = format!("sha256:{}", sha256_hex(&body));
+    let output = rget(&[&[url.as_str(), "-s", savepath.to_str().unwrap(), "--hash", &hash], args].concat());
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    assert!(
+        output.status.success(),
+        "rget failed:\n{}\n{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(std::fs::read(&savepath).unwrap() == body, "downloaded file differs");
+    let _ = std::fs::remove_dir_all(&dir);
+    stdout
+}
+
+#[test]
+fn single_threaded_download() {
+    let stdout = download("single", ServerOptions::default(), "1");
+    assert!(stdout.contains("Using single-threaded download"), "{}", stdout);
+}
+
+#[test]
+fn multi_threaded_download() {
+    let stdout = download("multi", ServerOptions::default(), "4");
+    assert!(stdout.contains("Using 4-threaded download"), "{}", stdout);
+}
+
+#[test]
+fn splits_odd_sizes_across_threads() {
+    // 默认小于1 MiB的文件走单线程，这里强制切成7段
+    for size in [1, 7, 1_000_003] {
+        let body = pattern()[..size].to_vec();
+        let args = ["-t", "7", "--prefer-single-thread-below", "0"];
+        let stdout = download_body("odd", body, ServerOptions::default(), &args);
+        if size >= 7 {
+            assert!(stdout.contains("Using 7-threaded download"), "{}", stdout);
+        }
+    }
+}
+
+#[test]
+fn sends_the_user_agent_verbatim() {
+    let server = serve(pattern(), ServerOptions::default());
+    let dir = temp_dir("agent");
+    let savepath = dir.join("out.bin");
+    let url = format!("http://127.0.0.1:{}/file.bin", server.port);
+    let output = rget(&[&url, "-s", savepath.to_str().unwrap(), "-t", "2", "--user-agent", "x/1 (y; z)"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let requests = server.requests();
+    assert!(requests.len() >= 3, "{:?}", requests);
+    for request in &requests {
+        assert_eq!(request.target, "/file.bin");
+        assert_eq!(request.header("user-agent"), Some("x/1 (y; z)"), "{:?}", request);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn sends_the_referer_on_probe_and_download() {
+    let server = serve(pattern(), ServerOptions::default());
+    let dir = temp_dir("referer");
+    let savepath = dir.join("out.bin");
+    let url = format!("http://127.0.0.1:{}/file.bin", server.port);
+    let output = rget(&[&url, "-s", savepath.to_str().unwrap(), "--referer", "https://example.com/page"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let requests = server.requests();
+    for method in ["HEAD", "GET"] {
+        assert!(requests.iter().any(|r| r.method == method), "no {} in {:?}", method, requests);
+    }
+    for request in &requests {
+        assert_eq!(request.header("referer"), Some("https://example.com/page"), "{:?}", request);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn asks_for_identity_unless_compression_is_enabled() {
+    let options = ServerOptions {
+        gzip: true,
+        ..ServerOptions::default()
+    };
+    let dir = temp_dir("gzip");
+    let savepath = dir.join("out.bin");
+    for (args, threads) in [(&[][..], "Using 4-threaded download"), (&["--compression"][..], "single-threaded")] {
+        let server = serve(pattern(), options);
+        let url = format!("http://127.0.0.1:{}/file.bin", server.port);
+        let output = rget(&[&[url.as_str(), "-s", savepath.to_str().unwrap(), "-f", "-t", "4"], args].concat());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(stdout.contains(threads), "{}", stdout);
+        assert!(std::fs::read(&savepath).unwrap() == pattern(), "downloaded file differs with {:?}", args);
+
+        let requests = server.requests();
+        for request in &requests {
+            let encoding = request.header("accept-encoding").unwrap_or_default();
+            if args.is_empty() {
+                assert_eq!(encoding, "identity", "{:?}", request);
+            } else {
+                assert!(encoding.contains("gzip"), "{:?}", request);
+            }
+        }
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn downloads_an_empty_file() {
+    for args in [&["-t", "4"][..], &["-t", "4", "--no-range-probe"]] {
+        download_body("empty", Vec::new(), ServerOptions::default(), args);
+    }
+    let without_length = ServerOptions {
+        content_length: false,
+        ..ServerOptions::default()
+    };
+    download_body("empty", Vec::new(), without_length, &["-t", "4"]);
+}
+
+#[test]
+fn falls_back_when_ranges_are_ignored() {
+    let options = ServerOptions {
+        ignore_range: true,
+        ..ServerOptions::default()
+    };
+    let port = serve(pattern(), options).port;
+    let dir = temp_dir("ignored");
+    let savepath = dir.join("out.bin");
+    let url = format!("http://127.0.0.1:{}/file.bin", port);
+    let output = rget(&[&url, "-s", savepath.to_str().unwrap(), "-t", "4"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("falling back to single-threaded download"), "{}", stderr);
+    assert!(std::fs::read(&savepath).unwrap() == pattern(), "downloaded file differs");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn custom_requests_download_single_threaded() {
+    for args in [["--method", "PUT"], ["--post-data", "a=1"]] {
+        let stdout = download_body("custom", pattern(), ServerOptions::default(), &[&args[..], &["-t", "4"]].concat());
+        assert!(stdout.contains("request, using single-threaded download"), "{}", stdout);
+        assert!(!stdout.contains("threaded download with chunk size"), "{}", stdout);
+    }
+}
+
+#[test]
+fn sends_post_bodies() {
+    let dir = temp_dir("post");
+    let savepath = dir.join("out.bin");
+    let body_file = dir.join("body.json");
+    std::fs::write(&body_file, b"{\"k\": [1, 2]}\n").unwrap();
+
+    let cases: [(&[&str], &str, &[u8]); 3] = [
+        (&["--post-data", "a=1&b=two"], "POST", b"a=1&b=two"),
+        (&["--post-file", body_file.to_str().unwrap()], "POST", b"{\"k\": [1, 2]}\n"),
+        (&["--post-data", "x", "--method", "PUT"], "PUT", b"x"),
+    ];
+    for (args, method, sent) in cases {
+        let server = serve(pattern(), ServerOptions::default());
+        let url = format!("http://127.0.0.1:{}/submit", server.port);
+        let output = rget(&[&[url.as_str(), "-s", savepath.to_str().unwrap(), "-f"], args].concat());
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(std::fs::read(&savepath).unwrap() == pattern(), "downloaded file differs");
+
+        // 带请求体时不发HEAD，只有一个请求
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1, "{:?}", requests);
+        assert_eq!(requests[0].method, method);
+        assert_eq!(requests[0].body, sent);
+        assert_eq!(requests[0].header("range"), None);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn compression_downloads_single_threaded() {
+    let stdout = download_body("compressed", pattern(), ServerOptions::default(), &["-t", "4", "--compression"]);
+    assert!(stdout.contains("Using single-threaded download"), "{}", stdout);
+}
+
+#[test]
+fn falls_back_without_range_support() {
+    let options = ServerOptions {
+        ranges: false,
+        ..ServerOptions::default()
+    };
+    let stdout = download("norange", options, "4");
+    assert!(stdout.contains("Server supports range requests: false"), "{}", stdout);
+    assert!(stdout.contains("Using single-threaded download"), "{}", stdout);
+}
+
+#[test]
+fn finds_size_from_content_range() {
+    let options = ServerOptions {
+        content_length: false,
+        ..ServerOptions::default()
+    };
+    let stdout = download("contentrange", options, "4");
+    assert!(stdout.contains("Using 4-threaded download"), "{}", stdout);
+}
+
+#[test]
+fn multi_threaded_with_chunked_encoding() {
+    let options = ServerOptions {
+        content_length: false,
+        chunked: true,
+        ..ServerOptions::default()
+    };
+    let stdout = download("chunked", options, "4");
+    assert!(stdout.contains("Using 4-threaded download"), "{}", stdout);
+}
+
+#[test]
+fn chunked_without_ranges_is_single_threaded() {
+    let options = ServerOptions {
+        ranges: false,
+        content_length: false,
+        chunked: true,
+        ..ServerOptions::default()
+    };
+    let stdout = download("chunked-norange", options, "4");
+    assert!(stdout.contains("using single-threaded download"), "{}", stdout);
+}
+
+#[test]
+fn probes_with_get_when_head_is_refused() {
+    let options = ServerOptions {
+        head: false,
+        ..ServerOptions::default()
+    };
+    let stdout = download("nohead", options, "4");
+    assert!(stdout.contains("Using 4-threaded download"), "{}", stdout);
+}
+
+#[test]
+fn picks_the_address_family() {
+    // 模拟服务器只监听127.0.0.1
+    let port = serve(pattern(), ServerOptions::default()).port;
+    let dir = temp_dir("family");
+    let savepath = dir.join("out.bin");
+    let url = format!("http://localhost:{}/file.bin", port);
+
+    let output = rget(&[&url, "-s", savepath.to_str().unwrap(), "-4"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(std::fs::read(&savepath).unwrap() == pattern(), "downloaded file differs");
+
+    let output = rget(&[&url, "-s", savepath.to_str().unwrap(), "-f", "-6", "--retries", "0"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "-6 reached an IPv4-only server");
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn downloads_through_a_proxy() {
+    let proxy = serve(pattern(), ServerOptions::default());
+    let dir = temp_dir("proxy");
+    let savepath = dir.join("out.bin");
+    let proxy_url = format!("http://127.0.0.1:{}", proxy.port);
+
+    // .invalid永远解析不了，请求只能经过代理
+    let url = "http://files.example.invalid/file.bin";
+    let output = rget(&[url, "-s", savepath.to_str().unwrap(), "-t", "4", "--proxy", &proxy_url]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(std::fs::read(&savepath).unwrap() == pattern(), "downloaded file differs");
+    let requests = proxy.requests();
+    assert!(requests.len() >= 5, "{:?}", requests);
+    for request in &requests {
+        assert_eq!(request.target, url, "not an absolute-form request: {:?}", request);
+        assert_eq!(request.header("host"), Some("files.example.invalid"), "{:?}", request);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn ignores_bytes_past_the_requested_range() {
+    let options = ServerOptions {
+        over_send: 1000,
+        ..ServerOptions::default()
+    };
+    let stdout = download("over-send", options, "4");
+    assert!(stdout.contains("4-threaded"), "{}", stdout);
+}
+
+#[test]
+fn refuses_a_range_that_starts_elsewhere() {
+    let options = ServerOptions {
+        shift_range: 100,
+        ..ServerOptions::default()
+    };
+    let port = serve(pattern(), options).port;
+    let dir = temp_dir("shifted-range");
+    let savepath = dir.join("out.bin");
+    let url = format!("http://127.0.0.1:{}/file.bin", port);
+
+    let output = rget(&[&url, "-s", savepath.to_str().unwrap(), "-t", "4", "--retries", "0"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Content-Range"));
+
+    // 单线程续传时也不能把错位的数据接到已有的部分后面
+    std::fs::write(dir.join("out.bin.part"), &pattern()[..1000]).unwrap();
+    let output = rget(&[&url, "-s", savepath.to_str().unwrap(), "-t", "1", "-c", "--retries", "0"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Can't resume at byte 1000"), "{}", stderr);
+    assert_eq!(std::fs::metadata(dir.join("out.bin.part")).unwrap().len(), 1000);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn credentials_stay_on_the_original_host() {
+    let mirror = serve(pattern(), ServerOptions::default());
+    let origin = serve(Vec::new(), ServerOptions { redirect_to: Some(mirror.port), ..ServerOptions::default() });
+    let dir = temp_dir("redirect-credentials");
+    let savepath = dir.join("out.bin");
+    let url = format!("http://127.0.0.1:{}/file.bin", origin.port);
+    let hash = format!("sha256:{}", sha256_hex(&pattern()));
+
+    let output = rget(&[
+        &url, "-s", savepath.to_str().unwrap(), "--hash", &hash, "-t", "4", "--user", "u", "--password", "p",
+        "--cookie", "session=1",
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // 每个分块请求都先发到原来的主机，再由重定向带到另一个主机
+    let requests = origin.requests();
+    assert!(requests.iter().all(|r| r.header("authorization").is_some() && r.header("cookie").is_some()));
+    let requests = mirror.requests();
+    assert!(requests.iter().filter(|r| r.header("range").is_some()).count() >= 4, "{:?}", requests);
+    assert!(
+        requests.iter().all(|r| r.header("authorization").is_none() && r.header("cookie").is_none()),
+        "{:?}",
+        requests
+    );
+}
+
+#[test]
+fn spider_checks_the_expected_file() {
+    let port = serve(pattern(), ServerOptions::default()).port;
+    let url = format!("http://127.0.0.1:{}/file.bin", port);
+
+    assert!(rget(&["--spider", &url]).status.success());
+    let output = rget(&["--spider", &url, "--max-filesize", "1000"]);
+    assert!(!output.status.success());
+    let output = rget(&["--spider", &url, "--expect-type", "text/html"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Content-Type"));
+}
+
+#[test]
+fn savepath_asks_with_the_cached_etag() {
+    let options = ServerOptions {
+        etag: Some("\"v1\""),
+        ..ServerOptions::default()
+    };
+    let dir = temp_dir("savepath-etag");
+    let savepath = dir.join("out.bin");
+    let cache = dir.join("cache");
+    let server = serve(pattern(), options);
+    let url = format!("http://127.0.0.1:{}/file.bin", server.port);
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rget"))
+            .args(["--no-config", "--no-progress", &url, "-s", savepath.to_str().unwrap()])
+            .args(args)
+            .env("XDG_CACHE_HOME", &cache)
+            .output()
+            .unwrap()
+    };
+
+    assert!(run(&[]).status.success());
+    // 文件已经存在，但缓存了它的ETag，先问服务器，没变就不用覆盖
+    let output = run(&[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("up to date"));
+
+    let output = run(&["--no-cache"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("file already exists"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn force_skips_the_cached_etag() {
+    let options = ServerOptions {
+        etag: Some("\"v1\""),
+        ..ServerOptions::default()
+    };
+    let dir = temp_dir("force");
+    let savepath = dir.join("out.bin");
+    let cache = dir.join("cache");
+    // ETag按URL缓存，几次下载都要用同一个端口
+    let server = serve(pattern(), options);
+    let url = format!("http://127.0.0.1:{}/file.bin", server.port);
+    // 返回这次运行发出的请求
+    let run = |args: &[&str]| {
+        let before = server.requests().len();
+        let output = Command::new(env!("CARGO_BIN_EXE_rget"))
+            .args(["--no-config", "--no-progress", &url, "-s", savepath.to_str().unwrap()])
+            .args(args)
+            .env("XDG_CACHE_HOME", &cache)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        server.requests().split_off(before)
+    };
+
+    // 第一次下载记下ETag，之后检查是否更新时带上它，服务器回304
+    run(&[]);
+    let requests = run(&["--if-modified-since"]);
+    assert_eq!(requests.len(), 1, "{:?}", requests);
+    assert_eq!(requests[0].header("if-none-match"), Some("\"v1\""));
+
+    let requests = run(&["-f"]);
+    assert!(requests.iter().all(|r| r.header("if-none-match").is_none()), "{:?}", requests);
+    assert!(requests.iter().all(|r| r.header("if-modified-since").is_none()), "{:?}", requests);
+    assert!(requests.iter().any(|r| r.method == "GET"), "--force didn't download again");
+
+    // 明确给了--if-modified-since时照样发，ETag还是不发
+    let requests = run(&["-f", "--if-modified-since"]);
+    assert_eq!(requests[0].header("if-none-match"), None, "{:?}", requests);
+    assert!(requests[0].header("if-modified-since").is_some(), "{:?}", requests);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn rejects_wrong_hash() {
+    let port = serve(pattern(), ServerOptions::default()).port;
+    let dir = temp_dir("badhash");
+    let savepath = dir.join("out.bin");
+
+    let url = format!("http://127.0.0.1:{}/file.bin", port);
+    let hash = format!("sha256:{}", "0".repeat(64));
+    let output = rget(&[&url, "-s", savepath.to_str().unwrap(), "--hash", &hash]);
+    assert!(!output.status.success());
+    assert!(!savepath.exists(), "file with a bad hash was kept");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn keeps_a_consistent_partial_download() {
+    let port = serve(pattern(), ServerOptions::default()).port;
+    let dir = temp_dir("partial");
+    let savepath = dir.join("out.bin");
+    let part = dir.join("out.bin.part");
+    let url = format!("http://127.0.0.1:{}/file.bin", port);
+
+    // 限速下3 MiB要十几秒，2秒时一定还没下完
+    let args = ["-t", "4", "--keep-partial", "--overall-timeout", "2", "--limit-rate", "200k"];
+    let output = rget(&[&[url.as_str(), "-s", savepath.to_str().unwrap()], &args[..]].concat());
+    assert!(!output.status.success());
+    assert!(!savepath.exists(), "unfinished download was renamed");
+    let data = std::fs::read(&part).unwrap();
+    assert_eq!(data.len(), SIZE, ".part isn't preallocated to the full size");
+
+    // 状态文件记录的分块首尾相接，每块已下载的部分和原文件一致
+    let state = std::fs::read_to_string(dir.join("out.bin.part.rget")).unwrap();
+    let mut lines = state.lines();
+    assert_eq!(lines.next(), Some(format!("total {}", SIZE).as_str()), "{}", state);
+    let (mut next, mut downloaded) = (0, 0);
+    for line in lines {
+        let fields: Vec<usize> = line.strip_prefix("chunk ").unwrap().split(' ').map(|f| f.parse().unwrap()).collect();
+        let [start, end, done] = fields[..] else { panic!("bad chunk line {:?}", line) };
+        assert_eq!(start, next, "{}", state);
+        assert!(done <= end - start + 1, "{}", state);
+        assert!(data[start..start + done] == pattern()[start..start + done], "chunk at {} has wrong bytes", start);
+        next = end + 1;
+        downloaded += done;
+    }
+    assert_eq!(next, SIZE, "{}", state);
+    assert!(downloaded < SIZE, "{}", state);
+
+    let output = rget(&[&url, "-s", savepath.to_str().unwrap(), "-t", "4", "--continue"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(std::fs::read(&savepath).unwrap() == pattern(), "resumed file differs");
+    assert!(!part.exists());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn downloads_a_byte_range() {
+    let body = pattern();
+    let port = serve(body.clone(), ServerOptions::default()).port;
+    let dir = temp_dir("range");
+    let url = format!("http://127.0.0.1:{}/file.bin", port);
+
+    for (range, expected) in [
+        ("1000-1999", &body[1000..2000]),
+        ("3000000-", &body[3_000_000..]),
+        ("-500", &body[SIZE - 500..]),
+    ] {
+        let savepath = dir.join("part.bin");
+        let output = rget(&[&url, "-s", savepath.to_str().unwrap(), "-f", "--range", range]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(std::fs::read(&savepath).unwrap() == expected, "wrong bytes for --range {}", range);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn reads_the_url_from_stdin() {
+    let body = pattern();
+    let port = serve(body.clone(), ServerOptions::default()).port;
+    let dir = temp_dir("stdin");
+    let savepath = dir.join("out.bin");
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rget"))
+        .args(["--no-config", "--no-progress", "-s", savepath.to_str().unwrap()])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    writeln!(child.stdin.take().unwrap(), "http://127.0.0.1:{}/file.bin", port).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(std::fs::read(&savepath).unwrap() == body, "downloaded file differs");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn single_thread_threshold_picks_the_strategy() {
+    let port = serve(pattern(), ServerOptions::default()).port;
+    let dir = temp_dir("threshold");
+    let savepath = dir.join("out.bin");
+    let url = format!("http://127.0.0.1:{}/file.bin", port);
+
+    // 文件大小正好等于阈值时已经不算"小于"
+    for (threshold, expected) in [
+        (SIZE, "Using 4-threaded download"),
+        (SIZE + 1, "Using single-threaded download"),
+    ] {
+        let threshold = threshold.to_string();
+        let output = rget(&[
+            &url,
+            "-s",
+            savepath.to_str().unwrap(),
+            "-f",
+            "-t",
+            "4",
+            "--prefer-single-thread-below",
+            &threshold,
+        ]);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(stdout.contains(expected), "threshold {}: {}", threshold, stdout);
+        assert!(std::fs::read(&savepath).unwrap() == pattern(), "downloaded file differs");
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn downloads_with_the_library_api() {
+    let port = serve(pattern(), ServerOptions::default()).port;
+    let dir = temp_dir("library");
+    let savepath = dir.join("out.bin");
+
+    let outcome = rget::Downloader::new(format!("http://127.0.0.1:{}/file.bin", port))
+        .savepath(savepath.to_str().unwrap())
+        .threads(4)
+        .hash(format!("sha256:{}", sha256_hex(&pattern())))
+        .download()
+        .await
+        .unwrap();
+    assert_eq!(outcome.total_bytes(), SIZE as u64);
+    assert_eq!(outcome.savepath(), savepath.to_str().unwrap());
+    assert_eq!(outcome.mode(), rget::DownloadMode::Multi { threads: 4 });
+    assert_eq!(outcome.hash_status(), rget::HashStatus::Verified);
+    assert!(std::fs::read(&savepath).unwrap() == pattern(), "downloaded file differs");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[cfg(unix)]
+#[test]
+fn downloads_over_a_unix_socket() {
+    let dir = temp_dir("unix");
+    let socket = dir.join("api.sock");
+    serve_unix(pattern(), ServerOptions::default(), &socket);
+    let savepath = dir.join("out.bin");
+
+    // 主机名不会被解析，只用在Host头里
+    let output = rget(&[
+        "http://localhost/file.bin",
+        "--unix-socket",
+        socket.to_str().unwrap(),
+        "-s",
+        savepath.to_str().unwrap(),
+        "-t",
+        "4",
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("Using 4-threaded download"), "{}", stdout);
+    assert!(std::fs::read(&savepath).unwrap() == pattern(), "downloaded file differs");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Download in 48 segments over 2 threads and count the connections the server saw
+fn connections_for(name: &str, extra: &[&str]) -> usize {
+    let server = serve(pattern(), ServerOptions::default());
+    let dir = temp_dir(name);
+    let savepath = dir.join("out.bin");
+
+    let url = format!("http://127.0.0.1:{}/file.bin", server.port);
+    let mut args = vec![url.as_str(), "-s", savepath.to_str().unwrap(), "-t", "2", "--segment-size", "64k"];
+    args.extend_from_slice(extra);
+    let output = rget(&args);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(std::fs::read(&savepath).unwrap() == pattern(), "downloaded file differs");
+    let _ = std::fs::remove_dir_all(&dir);
+    server.connections.load(Ordering::SeqCst)
+}
+
+#[test]
+fn shares_one_client_across_chunks() {
+    // HEAD加上48个分块请求，每个分块各建一个客户端的话连接数会接近49
+    let pooled = connections_for("pooled", &[]);
+    assert!(pooled <= 4, "{} connections with a shared client", pooled);
+}
+
+#[test]
+fn opens_a_connection_per_chunk_without_pooling() {
+    let unpooled = connections_for("unpooled", &["--pool-max-idle-per-host", "0"]);
+    assert!(unpooled > 40, "{} connections without pooling", unpooled);
+}